@@ -0,0 +1,48 @@
+#![allow(clippy::needless_return)]
+
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH}
+};
+
+// Build metadata printed by --version, "unknown" when the source is not a git checkout
+fn main () {
+    let git_commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let build_epoch = env::var("SOURCE_DATE_EPOCH").ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs()));
+
+    println!("cargo:rustc-env=DNSLIAR_GIT_COMMIT={}", git_commit);
+    println!("cargo:rustc-env=DNSLIAR_BUILD_DATE={}", civil_date(build_epoch / 86400));
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+// Days since 1970-01-01 to a YYYY-MM-DD date, from Howard Hinnant's civil_from_days
+fn civil_date (
+    days: u64
+)
+-> String {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    return format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -0,0 +1,170 @@
+use crate::enums_structs::{DnsLrResult, WrappedErrors, ErrorKind};
+use crate::matching;
+use crate::redis_mod;
+
+use arc_swap::ArcSwap;
+use serde_json::{Map, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fs
+};
+use tracing::{info, warn};
+
+// The entry of the backend file listing the domains of each matchclass, every other entry is a setting
+const MATCHES_ENTRY: &str = "matches";
+
+// Where the config and the matchclasses are read from, the Redis hashes or a local JSON file
+#[async_trait::async_trait]
+pub trait Backend: Send + Sync {
+    // The fields of a setting, a scalar setting has a single one
+    async fn fields (&self, kind: &str, daemon_id: &str) -> DnsLrResult<Vec<String>>;
+
+    // The fields of a setting with their values
+    async fn entries (&self, kind: &str, daemon_id: &str) -> DnsLrResult<HashMap<String, String>>;
+
+    // Whether each of the "{matchclass}:{domain}" keys built by redis_mod::match_key is listed
+    async fn exists_batch (&self, keys: &[String]) -> DnsLrResult<Vec<bool>>;
+
+    // Picks up the changes made since the last read, called before every reload
+    async fn refresh (&self) -> DnsLrResult<()> {
+        Ok(())
+    }
+}
+
+struct FileData {
+    settings: Map<String, Value>,
+    matches: HashSet<String>
+}
+
+// A JSON object with one entry per setting, named like the Redis hashes without the daemon_id suffix:
+// {"forwarders": ["9.9.9.9:53"], "binds": ["UDP=0.0.0.0:53"], "dnssec": true, "matchclasses": ["ads"],
+//  "blackhole_ips_ads": {"0.0.0.0": "1"}, "matches": {"ads": ["doubleclick.net", ".adnxs.com"]}}
+// The file is only read at startup and on reloads, a single node needs no Redis for its filtering this way
+pub struct FileBackend {
+    path: String,
+    data: ArcSwap<FileData>
+}
+impl FileBackend {
+    pub fn load (
+        path: &str
+    )
+    -> DnsLrResult<FileBackend> {
+        let backend = FileBackend {
+            path: path.to_string(),
+            data: ArcSwap::from_pointee(read_file(path)?)
+        };
+        info!("Backend file: {}", path);
+        return Ok(backend)
+    }
+
+    fn setting (
+        &self,
+        kind: &str
+    )
+    -> Option<Value> {
+        return self.data.load().settings.get(kind).cloned()
+    }
+}
+
+fn read_file (
+    path: &str
+)
+-> DnsLrResult<FileData> {
+    let invalid = |reason: String| WrappedErrors::DNSlrError(ErrorKind::ConfileInvalid {path: path.to_string(), reason});
+
+    let data = match fs::read_to_string(path) {
+        Ok(ok) => ok,
+        Err(error) => return Err(WrappedErrors::DNSlrError(ErrorKind::ConfileNotReadable {path: path.to_string(), source: error}))
+    };
+    let mut settings = match serde_json::from_str::<Value>(&data) {
+        Ok(Value::Object(settings)) => settings,
+        Ok(_) => return Err(invalid("the backend file must hold a JSON object".to_string())),
+        Err(error) => return Err(invalid(error.to_string()))
+    };
+
+    // Stored in the form the queries are matched with, like the keys written to Redis
+    let mut matches = HashSet::new();
+    if let Some(entry) = settings.remove(MATCHES_ENTRY) {
+        let Value::Object(matchclasses) = entry else {
+            return Err(invalid(format!("{} must map each matchclass to its domains", MATCHES_ENTRY)))
+        };
+        for (matchclass, domains) in matchclasses {
+            for domain in domains.as_array().into_iter().flatten() {
+                match domain.as_str().and_then(matching::normalize_entry) {
+                    Some(domain) => {
+                        matches.insert(redis_mod::match_key(&matchclass, &domain));
+                    },
+                    None => warn!("Backend file: domain: {} of matchclass: {} is not valid", domain, matchclass)
+                }
+            }
+        }
+    }
+
+    return Ok(FileData { settings, matches })
+}
+
+// Strings are taken as they are, numbers and booleans in their JSON form
+fn to_field (
+    value: &Value
+)
+-> Option<String> {
+    return match value {
+        Value::String(string) => Some(string.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for FileBackend {
+    async fn fields (
+        &self,
+        kind: &str,
+        _daemon_id: &str
+    )
+    -> DnsLrResult<Vec<String>> {
+        Ok(match self.setting(kind) {
+            Some(Value::Array(values)) => values.iter().filter_map(to_field).collect(),
+            Some(Value::Object(entries)) => entries.keys().cloned().collect(),
+            Some(value) => to_field(&value).into_iter().collect(),
+            None => vec![]
+        })
+    }
+
+    async fn entries (
+        &self,
+        kind: &str,
+        _daemon_id: &str
+    )
+    -> DnsLrResult<HashMap<String, String>> {
+        Ok(match self.setting(kind) {
+            Some(Value::Object(entries)) => entries.iter()
+                .map(|(field, value)| (field.clone(), to_field(value).unwrap_or_default()))
+                .collect(),
+            // A list is a hash whose fields have no value
+            Some(Value::Array(values)) => values.iter()
+                .filter_map(to_field)
+                .map(|field| (field, String::new()))
+                .collect(),
+            _ => HashMap::new()
+        })
+    }
+
+    async fn exists_batch (
+        &self,
+        keys: &[String]
+    )
+    -> DnsLrResult<Vec<bool>> {
+        let data = self.data.load();
+        Ok(keys.iter().map(|key| data.matches.contains(key)).collect())
+    }
+
+    // A file that became unreadable or invalid leaves the previous content in use
+    async fn refresh (
+        &self
+    )
+    -> DnsLrResult<()> {
+        self.data.store(read_file(&self.path)?.into());
+        Ok(())
+    }
+}
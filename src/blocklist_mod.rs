@@ -0,0 +1,107 @@
+use crate::enums_structs::Config;
+use crate::import_mod;
+use crate::redis_mod;
+
+use redis::aio::ConnectionManager;
+use reqwest::{
+    Client, StatusCode,
+    header::{ETAG, LAST_MODIFIED, IF_NONE_MATCH, IF_MODIFIED_SINCE}
+};
+use tracing::{info, warn, debug};
+use std::{
+    collections::HashMap,
+    time::Duration
+};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+// ETag and Last-Modified of the last list stored, per URL
+#[derive(Default, Clone)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>
+}
+
+pub struct BlocklistFetcher {
+    client: Client,
+    validators: HashMap<String, Validators>
+}
+impl BlocklistFetcher {
+    pub fn new ()
+    -> BlocklistFetcher {
+        return BlocklistFetcher {
+            client: Client::builder().timeout(FETCH_TIMEOUT).build().unwrap_or_default(),
+            validators: HashMap::new()
+        }
+    }
+
+    // Returns None when the list did not change since the last fetch
+    async fn fetch (
+        &self,
+        url: &str
+    )
+    -> Result<Option<(String, Validators)>, reqwest::Error> {
+        let mut request = self.client.get(url);
+        if let Some(validators) = self.validators.get(url) {
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag)
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified)
+            }
+        }
+
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None)
+        }
+        let response = response.error_for_status()?;
+
+        let headers = response.headers();
+        let validators = Validators {
+            etag: headers.get(ETAG).and_then(|value| value.to_str().ok()).map(str::to_string),
+            last_modified: headers.get(LAST_MODIFIED).and_then(|value| value.to_str().ok()).map(str::to_string)
+        };
+        return Ok(Some((response.text().await?, validators)))
+    }
+
+    // Returns true when at least one matchclass was updated in Redis
+    pub async fn refresh (
+        &mut self,
+        manager: &mut ConnectionManager,
+        config: &Config
+    )
+    -> bool {
+        let mut updated = false;
+
+        for (matchclass, url) in config.blocklists.iter() {
+            let (contents, validators) = match self.fetch(url).await {
+                Ok(Some(fetched)) => fetched,
+                Ok(None) => {
+                    debug!("{}: Blocklist: {} did not change", config.daemon_id, url);
+                    continue
+                },
+                Err(error) => {
+                    warn!("{}: Could not fetch the blocklist: {}, keeping the previous one: {}", config.daemon_id, url, error);
+                    continue
+                }
+            };
+
+            let summary = import_mod::parse_hosts(&contents);
+            if summary.domains.is_empty() {
+                warn!("{}: Blocklist: {} has no valid entry, keeping the previous one", config.daemon_id, url);
+                continue
+            }
+            if let Err(error) = redis_mod::replace_blocklist(manager, matchclass, &config.daemon_id, &summary.domains).await {
+                warn!("{}: Could not store the blocklist: {}: {}", config.daemon_id, url, error);
+                continue
+            }
+
+            info!("{}: Blocklist: {} refreshed, skipped {} lines and {} malformed entries", config.daemon_id, url, summary.skipped, summary.malformed);
+            self.validators.insert(url.clone(), validators);
+            updated = true
+        }
+
+        return updated
+    }
+}
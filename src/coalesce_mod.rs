@@ -0,0 +1,73 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex}
+};
+use tokio::sync::broadcast;
+use trust_dns_proto::{
+    op::ResponseCode,
+    rr::{DNSClass, Record, RecordType}
+};
+
+// The normalized query name, the qtype and the class, so every casing of a query joins the same one
+// trust-dns hands the names of the queries and of the answers lowercased, the followers share the records as they are
+pub type Key = (String, RecordType, DNSClass);
+// What the followers get from the leader, the header of each answer is their own
+type Shared = (Vec<Record>, ResponseCode);
+
+// Identical queries forwarded at the same time, the first one goes upstream and the others wait for its answer
+#[derive(Default)]
+pub struct Coalescer {
+    inflight: Arc<Mutex<HashMap<Key, broadcast::Sender<Shared>>>>
+}
+
+pub enum Role {
+    Leader(Leader),
+    Follower(broadcast::Receiver<Shared>)
+}
+
+// Removes the query from the in-flight ones when dropped, whether it was answered or cancelled
+pub struct Leader {
+    inflight: Arc<Mutex<HashMap<Key, broadcast::Sender<Shared>>>>,
+    key: Key,
+    sender: broadcast::Sender<Shared>
+}
+impl Leader {
+    // Failed forwards are not shared, the followers then find the channel closed and forward by themselves
+    pub fn share (
+        self,
+        shared: Shared
+    ) {
+        self.inflight.lock().unwrap().remove(&self.key);
+        let _ = self.sender.send(shared);
+    }
+}
+impl Drop for Leader {
+    fn drop (&mut self) {
+        let mut inflight = self.inflight.lock().unwrap();
+        // A later leader may have taken the key once this one shared its answer
+        if inflight.get(&self.key).is_some_and(|sender| sender.same_channel(&self.sender)) {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
+impl Coalescer {
+    pub fn join (
+        &self,
+        key: Key
+    )
+    -> Role {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(sender) = inflight.get(&key) {
+            return Role::Follower(sender.subscribe())
+        }
+
+        let (sender, _) = broadcast::channel(1);
+        inflight.insert(key.clone(), sender.clone());
+        return Role::Leader(Leader {
+            inflight: Arc::clone(&self.inflight),
+            key,
+            sender
+        })
+    }
+}
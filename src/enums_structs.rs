@@ -1,14 +1,20 @@
 use core::fmt;
 use std::{
+    collections::HashMap,
     fmt::{Display, Formatter},
-    net::{SocketAddr, Ipv6Addr, Ipv4Addr},
+    net::{SocketAddr, IpAddr, Ipv6Addr, Ipv4Addr},
+    time::Duration,
+    sync::atomic::{AtomicU64, Ordering},
     io
 };
 use serde::{Serialize, Deserialize};
 
+use crate::socket_mod;
+use crate::top_blocked_mod::TopBlocked;
+
 use tokio::task::JoinError;
 use trust_dns_resolver::error::ResolveError;
-use trust_dns_proto::error::ProtoError;
+use trust_dns_proto::{error::ProtoError, op::{OpCode, ResponseCode}, rr::{Name, RecordType}};
 use redis::RedisError;
 
 pub type DnsLrResult<T> = std::result::Result<T, WrappedErrors>;
@@ -16,17 +22,489 @@ pub type DnsLrResult<T> = std::result::Result<T, WrappedErrors>;
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Confile {
     pub daemon_id: String,
+    // "redis" by default, "file" reads the config and the matchclasses from backend_file instead
+    pub backend: Option<String>,
+    // The config file itself when unset, its own entries are then ignored by the backend
+    pub backend_file: Option<String>,
+    #[serde(default)]
     pub redis_address: String,
+    pub redis_username: Option<String>,
+    pub redis_password: Option<String>,
+    #[serde(default)]
+    pub redis_tls: bool,
+    pub redis_ca_cert: Option<String>,
+    pub redis_max_attempts: Option<u32>,
+    pub redis_retry_delay_ms: Option<u64>,
+    pub redis_pool_size: Option<usize>,
+    pub redis_db: Option<i64>,
+    pub key_prefix: Option<String>,
+    pub tls_certificate: Option<String>,
+    pub tls_private_key: Option<String>,
+    pub https_hostname: Option<String>,
+    pub dnssec_trust_anchor: Option<String>,
+    pub edns_payload_size: Option<u16>,
+    pub log_format: Option<String>,
+    pub log_level: Option<String>,
+    // OTLP/HTTP traces endpoint, e.g. "http://127.0.0.1:4318/v1/traces", queries are not traced without it
+    pub otlp_endpoint: Option<String>,
+    // "multi_thread" by default or "current_thread", worker_threads defaults to one per core and is ignored by the latter
+    pub runtime_flavor: Option<String>,
+    pub worker_threads: Option<usize>
+}
+impl Confile {
+    pub fn is_file_backend (&self) -> bool {
+        return self.backend.as_deref() == Some("file")
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config { 
     pub daemon_id: String,
-    pub forwarders: Vec<SocketAddr>,
+    pub forwarders: Vec<Forwarder>,
+    // Names under one of these zones go to its forwarders instead, the deepest zone first
+    pub forward_zones: Vec<(Name, Vec<Forwarder>)>,
+    pub parallel_forwarding: bool,
+    pub forwarder_strategy: Option<ForwarderStrategy>,
+    // Local address the queries to the forwarders leave from, those of the other family leave from any
+    pub forwarder_source_ip: Option<IpAddr>,
+    // Probe interval and the failed probes in a row after which a forwarder is left out
+    pub forwarder_probe: Option<(Duration, u32)>,
+    // Name looked up through the forwarders once the binds are up, and whether a failure stops the server
+    pub selftest: Option<(Name, bool)>,
+    // How long the resolver waits on a forwarder and how many times it tries, the trust-dns defaults when unset
+    pub upstream_timeout: Option<Duration>,
+    pub upstream_attempts: Option<usize>,
+    pub dnssec: bool,
+    pub ecs: Option<(u8, u8)>,
+    pub cache_size: Option<usize>,
+    pub positive_cache: bool,
+    pub negative_cache: bool,
+    pub cache_min_ttl: Option<u32>,
+    pub cache_max_ttl: Option<u32>,
+    // Bounds on the TTLs of the forwarded answers, whatever the resolver cache keeps
+    pub min_ttl: Option<u32>,
+    pub max_ttl: Option<u32>,
     pub binds: Vec<String>,
+    // The plain UDP and TCP binds are skipped when their transport is disabled, the encrypted ones are kept
+    pub enable_udp: bool,
+    pub enable_tcp: bool,
+    // Binds with SO_REUSEPORT so that several processes can share the same addresses
+    pub reuseport: bool,
+    pub tcp_timeout: Duration,
+    // How long the queries in flight at shutdown are given to finish before they are cancelled
+    pub drain_timeout: Duration,
+    // Idle timeout advertised to the TCP and TLS clients sending an EDNS keepalive option
+    pub tcp_keepalive: Option<Duration>,
+    pub metrics_bind: Option<SocketAddr>,
+    pub health_bind: Option<SocketAddr>,
+    pub stats_file: Option<String>,
+    // How often the counters are flushed to the stats_<daemon_id> hash, they are restored from it on startup
+    pub stats_persist: Option<Duration>,
+    pub query_log: bool,
+    // TXT queries on "<name>._dnsliar." answer whether the name is filtered
+    pub debug_queries: bool,
+    // Answers only carry the records of the queried type and their CNAMEs, NXDOMAIN lies lose their SOA
+    pub minimal_responses: bool,
+    pub any_queries: AnyQueries,
+    // The qtypes outside resolver_mod::RESOLVED_QTYPES get an RFC 8482 HINFO instead of going to the forwarders
+    pub unsupported_qtypes_hinfo: bool,
+    // CHAOS TXT answers, version.bind gets chaos_version and hostname.bind the daemon_id unless it is hidden
+    pub chaos_version: String,
+    pub chaos_hide_id: bool,
+    pub rate_limit: Option<(u32, u32)>,
+    pub max_inflight: Option<usize>,
+    // One dropped malformed query in this many is logged at debug with its source, 0 logs none
+    pub malformed_log_sample: u64,
+    // Responses allowed per client subnet and response kind in a window, and how often limited ones slip
+    pub rrl: Option<(u32, Duration, u32)>,
+    pub allowed_networks: Vec<Network>,
+    // Trusted clients, their queries are never filtered
+    pub bypass_networks: Vec<Network>,
+    pub redis_failure_mode: RedisFailureMode,
+    pub pubsub_reload: bool,
+    pub static_records: bool,
+    pub static_ttl: u32,
+    pub blocklists: Vec<(String, String)>,
+    pub blocklist_refresh: Duration,
     pub is_filtering: bool,
-    pub matchclasses: Option<Vec<String>>,
-    pub blackhole_ips: Option<(Ipv4Addr, Ipv6Addr)>
+    pub filter_mode: FilterMode,
+    // Used instead of filter_mode for the SVCB and HTTPS queries, which blackhole_ip answers without records
+    pub svcb_filter_mode: Option<SvcbFilterMode>,
+    // RFC 8914 error attached to the filtered answers of EDNS clients and its text, the matchclass name when unset
+    pub extended_error: Option<(ExtendedError, Option<String>)>,
+    pub blackhole_ttl: u32,
+    pub blackhole_ptr: String,
+    pub soa_minimum_ttl: u32,
+    pub soa_mname: String,
+    pub soa_rname: String,
+    // The load time of the filtering settings, so the serial advances with every reload
+    pub soa_serial: u32,
+    pub subdomain_matching: bool,
+    // Whether the ".{domain}" wildcard keys of the name and its ancestors are checked too
+    pub wildcard_matching: bool,
+    // Size of the in-process cache of match decisions and how long a decision is kept
+    pub match_cache: Option<(usize, Duration)>,
+    pub matchclasses: Option<Vec<Matchclass>>,
+    // Asked about the queries no matchclass decided, the local matchclasses allow or block first
+    pub policy_service: Option<PolicyService>,
+    // Matchclasses of the binds given a "#policy" suffix, used instead of the matchclasses above
+    pub policies: HashMap<String, Vec<Matchclass>>,
+    // A family without blackhole IPs answers its filtered queries without records
+    pub blackhole_ips: Option<(Vec<Ipv4Addr>, Vec<Ipv6Addr>)>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Matchclass {
+    pub name: String,
+    pub is_allow: bool,
+    // The reserved NXDOMAIN list, answered with NXDOMAIN whatever the filter_mode and before the allow matchclasses
+    pub is_nxdomain: bool,
+    // A redirect of the policy service, answered with its blackhole IPs of the queried family whatever the filter_mode
+    pub is_redirect: bool,
+    // Sinks replacing the global blackhole IPs of their family, each lie picks one according to the weights
+    pub blackhole_ipv4s: Vec<(Ipv4Addr, u32)>,
+    pub blackhole_ipv6s: Vec<(Ipv6Addr, u32)>,
+    pub qtypes: QtypeFilter,
+    pub match_mode: MatchMode
+}
+
+// How a matchclass matches the query name
+// Exact and suffix look up "{matchclass}:{domain}" keys, suffix adds one lookup per ancestor to the same pipeline
+// Contains keeps its tokens in memory and scans all of them on every query, they are read with the matchclasses
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum MatchMode {
+    Exact,
+    Suffix,
+    Contains(Vec<String>)
+}
+
+// The query types a matchclass applies to, queries of any other type are not checked against it
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum QtypeFilter {
+    All,
+    Only(Vec<RecordType>)
+}
+impl QtypeFilter {
+    pub fn covers (
+        &self,
+        qtype: RecordType
+    )
+    -> bool {
+        return match self {
+            QtypeFilter::All => true,
+            // Browsers would otherwise reach a name filtered for its addresses through the hints of its HTTPS record
+            QtypeFilter::Only(qtypes) => qtypes.contains(&qtype)
+                || (matches!(qtype, RecordType::SVCB | RecordType::HTTPS) && (qtypes.contains(&RecordType::A) || qtypes.contains(&RecordType::AAAA)))
+        }
+    }
+}
+
+// What the process was started to do, read from the command line
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Command {
+    Serve,
+    CheckConfig,
+    ImportHosts(String, String),
+    ListMatchclass(String)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Network {
+    pub address: IpAddr,
+    pub prefix: u8
+}
+impl Network {
+    // Parses "10.0.0.0/8" or "2001:db8::/32", a bare address is a single host
+    pub fn parse (
+        network: &str
+    )
+    -> Option<Network> {
+        let (address, prefix) = match network.split_once('/') {
+            Some((address, prefix)) => (address.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (network.parse::<IpAddr>().ok()?, None)
+        };
+        let max_prefix = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return None
+        }
+        return Some(Network { address, prefix })
+    }
+
+    pub fn contains (
+        &self,
+        ip: IpAddr
+    )
+    -> bool {
+        return match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+// Plain forwarders are queried over UDP and TCP, the others over an encrypted transport checked against tls_name
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ForwarderProtocol {
+    Plain,
+    Tls,
+    Https
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Forwarder {
+    pub address: SocketAddr,
+    pub protocol: ForwarderProtocol,
+    pub tls_name: Option<String>
+}
+impl Forwarder {
+    // Parses "ip:port" for a plain forwarder, "tls://ip[:port]#name" or "https://ip[:port]#name" for an encrypted one
+    // The port of an encrypted forwarder defaults to 853 for TLS and 443 for HTTPS
+    pub fn parse (
+        forwarder: &str
+    )
+    -> Option<Forwarder> {
+        let (protocol, default_port, rest) = match forwarder.split_once("://") {
+            None => return Some(Forwarder {
+                address: socket_mod::parse_address(forwarder).ok()?,
+                protocol: ForwarderProtocol::Plain,
+                tls_name: None
+            }),
+            Some(("tls", rest)) => (ForwarderProtocol::Tls, 853, rest),
+            Some(("https", rest)) => (ForwarderProtocol::Https, 443, rest),
+            Some(_) => return None
+        };
+
+        let (address, tls_name) = rest.split_once('#')?;
+        if tls_name.is_empty() {
+            return None
+        }
+        // Without a port the address is a bare IP, in brackets or not when it is an IPv6 one
+        let address = match socket_mod::parse_address(address) {
+            Ok(address) => address,
+            Err(_) if address.starts_with('[') || !address.contains(':') => socket_mod::parse_address(&format!("{}:{}", address, default_port)).ok()?,
+            Err(_) => socket_mod::parse_address(&format!("[{}]:{}", address, default_port)).ok()?
+        };
+        return Some(Forwarder {
+            address,
+            protocol,
+            tls_name: Some(tls_name.to_string())
+        })
+    }
+}
+impl Display for Forwarder {
+    fn fmt (&self, f: &mut Formatter) -> fmt::Result {
+        match (self.protocol, &self.tls_name) {
+            (ForwarderProtocol::Tls, Some(tls_name)) => write!(f, "tls://{}#{}", self.address, tls_name),
+            (ForwarderProtocol::Https, Some(tls_name)) => write!(f, "https://{}#{}", self.address, tls_name),
+            _ => write!(f, "{}", self.address)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ForwarderStrategy {
+    Failover,
+    RoundRobin,
+    Random
+}
+
+// What a query gets when its matchclass or static record lookup cannot reach Redis
+// Fail open forwards it unfiltered, fail closed answers it with SERVFAIL
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RedisFailureMode {
+    FailOpen,
+    FailClosed
+}
+
+// An HTTP endpoint deciding on queries, gRPC is not supported
+// A failed or late answer forwards the query unfiltered when failing open, and filters it when failing closed
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct PolicyService {
+    pub url: String,
+    pub timeout: Duration,
+    pub cache_ttl: Duration,
+    pub fail_open: bool
+}
+
+// What ANY queries get, hinfo answers them the RFC 8482 way
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AnyQueries {
+    Pass,
+    Hinfo,
+    Refused
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FilterMode {
+    BlackholeIp,
+    Nxdomain,
+    Refused
+}
+
+// What filtered SVCB and HTTPS queries get, they carry no address a blackhole IP could stand for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SvcbFilterMode {
+    Nodata,
+    Nxdomain,
+    Refused
+}
+
+// The RFC 8914 info codes a filtered answer can carry
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExtendedError {
+    Blocked,
+    Censored,
+    Filtered
+}
+impl ExtendedError {
+    pub fn info_code (&self) -> u16 {
+        return match self {
+            ExtendedError::Blocked => 15,
+            ExtendedError::Censored => 16,
+            ExtendedError::Filtered => 17
+        }
+    }
+}
+
+pub const LATENCY_BUCKETS_MS: [u64; 11] = [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+#[derive(Debug, Default)]
+pub struct Histogram {
+    pub buckets: [AtomicU64; 11],
+    pub sum_us: AtomicU64,
+    pub count: AtomicU64
+}
+impl Histogram {
+    pub fn observe (&self, duration: Duration) {
+        let duration_us = duration.as_micros() as u64;
+        if let Some(index) = LATENCY_BUCKETS_MS.iter().position(|bound| duration_us <= bound * 1000) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_us.fetch_add(duration_us, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reset (&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed)
+        }
+        self.sum_us.store(0, Ordering::Relaxed);
+        self.count.store(0, Ordering::Relaxed)
+    }
+}
+
+// The response codes counted on their own with their metric labels, the others are summed under OTHER
+pub const RESPONSE_CODES: [(ResponseCode, &str); 6] = [
+    (ResponseCode::NoError, "NOERROR"),
+    (ResponseCode::FormErr, "FORMERR"),
+    (ResponseCode::ServFail, "SERVFAIL"),
+    (ResponseCode::NXDomain, "NXDOMAIN"),
+    (ResponseCode::NotImp, "NOTIMP"),
+    (ResponseCode::Refused, "REFUSED")
+];
+
+#[derive(Debug, Default)]
+pub struct ResponseCodes {
+    pub counts: [AtomicU64; 7]
+}
+impl ResponseCodes {
+    pub fn observe (&self, response_code: ResponseCode) {
+        let index = RESPONSE_CODES.iter().position(|(counted, _)| *counted == response_code).unwrap_or(RESPONSE_CODES.len());
+        self.counts[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reset (&self) {
+        for count in self.counts.iter() {
+            count.store(0, Ordering::Relaxed)
+        }
+    }
+
+    // Each label with its count, OTHER last
+    pub fn labeled (&self) -> Vec<(&'static str, u64)> {
+        return RESPONSE_CODES.iter().map(|(_, label)| *label)
+            .chain(["OTHER"])
+            .zip(self.counts.iter().map(|count| count.load(Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub queries: AtomicU64,
+    pub filtered: AtomicU64,
+    pub forwarded: AtomicU64,
+    pub nxdomains: AtomicU64,
+    pub forward_errors: AtomicU64,
+    pub redis_errors: AtomicU64,
+    pub rate_limited: AtomicU64,
+    pub static_answers: AtomicU64,
+    pub unauthorized: AtomicU64,
+    pub overloaded: AtomicU64,
+    pub rrl_limited: AtomicU64,
+    pub match_cache_hits: AtomicU64,
+    pub match_cache_misses: AtomicU64,
+    pub coalesced: AtomicU64,
+    pub malformed_dropped: AtomicU64,
+    pub policy_service_errors: AtomicU64,
+    pub resolver_latency: Histogram,
+    pub response_codes: ResponseCodes,
+    pub top_blocked: TopBlocked
+}
+impl Stats {
+    pub fn reset (&self) {
+        self.queries.store(0, Ordering::Relaxed);
+        self.filtered.store(0, Ordering::Relaxed);
+        self.forwarded.store(0, Ordering::Relaxed);
+        self.nxdomains.store(0, Ordering::Relaxed);
+        self.forward_errors.store(0, Ordering::Relaxed);
+        self.redis_errors.store(0, Ordering::Relaxed);
+        self.rate_limited.store(0, Ordering::Relaxed);
+        self.static_answers.store(0, Ordering::Relaxed);
+        self.unauthorized.store(0, Ordering::Relaxed);
+        self.overloaded.store(0, Ordering::Relaxed);
+        self.rrl_limited.store(0, Ordering::Relaxed);
+        self.match_cache_hits.store(0, Ordering::Relaxed);
+        self.match_cache_misses.store(0, Ordering::Relaxed);
+        self.coalesced.store(0, Ordering::Relaxed);
+        self.malformed_dropped.store(0, Ordering::Relaxed);
+        self.policy_service_errors.store(0, Ordering::Relaxed);
+        self.resolver_latency.reset();
+        self.response_codes.reset();
+        self.top_blocked.reset()
+    }
+
+    // The counters under the names they are persisted with
+    pub fn counters (&self) -> [(&'static str, &AtomicU64); 16] {
+        return [
+            ("queries", &self.queries),
+            ("filtered", &self.filtered),
+            ("forwarded", &self.forwarded),
+            ("nxdomains", &self.nxdomains),
+            ("forward_errors", &self.forward_errors),
+            ("redis_errors", &self.redis_errors),
+            ("rate_limited", &self.rate_limited),
+            ("static_answers", &self.static_answers),
+            ("unauthorized", &self.unauthorized),
+            ("overloaded", &self.overloaded),
+            ("rrl_limited", &self.rrl_limited),
+            ("match_cache_hits", &self.match_cache_hits),
+            ("match_cache_misses", &self.match_cache_misses),
+            ("coalesced", &self.coalesced),
+            ("malformed_dropped", &self.malformed_dropped),
+            ("policy_service_errors", &self.policy_service_errors)
+        ]
+    }
 }
 
 #[derive(Debug)]
@@ -39,20 +517,94 @@ pub enum WrappedErrors {
     JoinError(JoinError)
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[derive(Debug)]
 pub enum ErrorKind {
-    InvalidOpCode,
-    InvalidMessageType,
-    InvalidArpaAddress,
-    SetupBindingError,
-    SetupForwardersError,
-    RequestRefused
+    InvalidOpCode(OpCode),
+    InvalidArpaAddress(String),
+    // One bind that could not be set, the others are still tried
+    BindFailed {
+        bind: String,
+        source: Box<WrappedErrors>
+    },
+    NoBindSet(Vec<ErrorKind>),
+    UnknownBindProtocol(String),
+    QuicPortConflict,
+    HttpsHostnameMissing,
+    TlsNotConfigured,
+    TlsMaterialUnreadable {
+        path: String,
+        source: ProtoError
+    },
+    DnssecTrustAnchorUnsupported(String),
+    RedisCaCertUnsupported(String),
+    InvalidRedisAddress(String),
+    RedisAuthError,
+    RedisTlsError(RedisError),
+    RedisConnectionError {
+        attempts: u32,
+        source: RedisError
+    },
+    RequestRefused,
+    InvalidArguments(String),
+    ConfileNotReadable {
+        path: String,
+        source: io::Error
+    },
+    ConfileInvalid {
+        path: String,
+        reason: String
+    },
+    ConfigValidation(Vec<String>),
+    RedisBackendRequired(String),
+    PolicyServiceFailed(String),
+    SelftestFailed {
+        name: String,
+        source: ResolveError
+    }
 }
 
+impl Display for ErrorKind {
+    fn fmt (&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::InvalidOpCode(op_code) => write!(f, "Op code {:?} is not supported", op_code),
+            ErrorKind::InvalidArpaAddress(name) => write!(f, "{} is not a valid reverse lookup name", name),
+            ErrorKind::BindFailed {bind, source} => write!(f, "Failed to bind: {}: {}", bind, source),
+            ErrorKind::NoBindSet(failures) => {
+                write!(f, "No bind was set")?;
+                for failure in failures {
+                    write!(f, "; {}", failure)?
+                }
+                Ok(())
+            },
+            ErrorKind::UnknownBindProtocol(protocol) => write!(f, "{} is not a known protocol, expecting UDP, TCP, TLS, HTTPS or QUIC", protocol),
+            ErrorKind::QuicPortConflict => write!(f, "A UDP bind already uses this port"),
+            ErrorKind::HttpsHostnameMissing => write!(f, "No https_hostname was set in the config file"),
+            ErrorKind::TlsNotConfigured => write!(f, "tls_certificate and tls_private_key must both be set in the config file"),
+            ErrorKind::TlsMaterialUnreadable {path, source} => write!(f, "Could not read {}: {}", path, source),
+            ErrorKind::DnssecTrustAnchorUnsupported(path) => write!(f, "DNSSEC trust anchor: {} cannot be loaded, only the built-in root KSK is supported", path),
+            ErrorKind::RedisCaCertUnsupported(path) => write!(f, "Redis CA certificate: {} cannot be loaded, the Redis TLS connector only trusts the system roots, add the CA to them instead", path),
+            ErrorKind::InvalidRedisAddress(address) => write!(f, "Redis address: {} is not valid", address),
+            ErrorKind::RedisAuthError => write!(f, "Authentication to Redis failed"),
+            ErrorKind::RedisTlsError(source) => write!(f, "TLS connection to Redis failed: {}", source),
+            ErrorKind::RedisConnectionError {attempts, source} => write!(f, "Could not connect to Redis after {} attempts: {}", attempts, source),
+            ErrorKind::RequestRefused => write!(f, "The forwarders refused the query"),
+            ErrorKind::InvalidArguments(reason) => write!(f, "Invalid arguments: {}", reason),
+            ErrorKind::ConfileNotReadable {path, source} => write!(f, "Could not read the config file {}: {}", path, source),
+            ErrorKind::ConfileInvalid {path, reason} => write!(f, "The config file {} is not valid: {}", path, reason),
+            ErrorKind::ConfigValidation(errors) => write!(f, "The config is not valid: {}", errors.join(", ")),
+            ErrorKind::RedisBackendRequired(feature) => write!(f, "{} needs the Redis backend", feature),
+            ErrorKind::PolicyServiceFailed(reason) => write!(f, "The policy service did not decide: {}", reason),
+            ErrorKind::SelftestFailed {name, source} => write!(f, "The startup self-test could not resolve {}: {}", name, source)
+        }
+    }
+}
+// Display already ends with the error that caused it, so none is returned as the source too
+impl std::error::Error for ErrorKind {}
+
 impl Display for WrappedErrors {
     fn fmt (&self, f: &mut Formatter) -> fmt::Result {
         match *self {
-            WrappedErrors::DNSlrError(ref error) => write!(f, "A DNSlr error occurred {:?}", error),
+            WrappedErrors::DNSlrError(ref error) => error.fmt(f),
             WrappedErrors::IOError(ref error) => error.fmt(f),
             WrappedErrors::RedisError(ref error) => error.fmt(f),
             WrappedErrors::ResolverError(ref error) => error.fmt(f),
@@ -61,6 +613,19 @@ impl Display for WrappedErrors {
         }
     }
 }
+// Displayed as the wrapped error, so its source is the one of the wrapped error
+impl std::error::Error for WrappedErrors {
+    fn source (&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            WrappedErrors::DNSlrError(ref error) => error.source(),
+            WrappedErrors::IOError(ref error) => error.source(),
+            WrappedErrors::RedisError(ref error) => error.source(),
+            WrappedErrors::ResolverError(ref error) => error.source(),
+            WrappedErrors::ProtoError(ref error) => error.source(),
+            WrappedErrors::JoinError(ref error) => error.source()
+        }
+    }
+}
 impl From<RedisError> for WrappedErrors {
     fn from (error: RedisError) -> WrappedErrors {
         WrappedErrors::RedisError(error)
@@ -1,21 +1,39 @@
-use crate::enums_structs::{Config, WrappedErrors, ErrorKind, DnsLrResult};
+use crate::enums_structs::{AnyQueries, Config, Matchclass, WrappedErrors, ErrorKind, DnsLrResult, Stats, RedisFailureMode};
 use crate::resolver_mod;
 use crate::matching;
+use crate::static_mod;
+use crate::redis_mod;
+use crate::telemetry_mod;
+use crate::rate_limit_mod::RateLimiter;
+use crate::rrl_mod::{ResponseRateLimiter, Verdict};
+use crate::match_cache_mod::{self, MatchCache};
+use crate::coalesce_mod::{self, Coalescer, Role};
+use crate::backend_mod::Backend;
+use crate::policy_service_mod::{PolicyServiceClient, Decision};
 
-use trust_dns_resolver::{
-    AsyncResolver,
-    name_server::{GenericConnection, GenericConnectionProvider, TokioRuntime}
-};
 use trust_dns_server::{
     server::{Request, RequestHandler, ResponseHandler, ResponseInfo},
-    proto::op::{Header, ResponseCode, OpCode, MessageType},
-    authority::MessageResponseBuilder
+    proto::op::{Edns, Header, Message, ResponseCode, OpCode, MessageType},
+    authority::MessageResponseBuilder,
+    server::Protocol
 };
-use trust_dns_proto::rr::{Record, RecordType};
+use trust_dns_proto::rr::{DNSClass, Record, RecordType, rdata::opt::EdnsCode};
 
 use arc_swap::ArcSwap;
-use std::sync::Arc;
-use tracing::error;
+use tokio::sync::Notify;
+use std::{
+    sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    time::Instant
+};
+use tracing::{error, warn, debug, Instrument, Span};
+
+// A question and an OPT record fit in 512 bytes, RFC 8467 padding included
+const MAX_QUERY_SIZE: usize = 512;
+// The header, the type and class of the question, the fixed fields of the OPT record and of each of its options
+const HEADER_SIZE: usize = 12;
+const QUESTION_FIXED_SIZE: usize = 4;
+const OPT_FIXED_SIZE: usize = 11;
+const EDNS_OPTION_FIXED_SIZE: usize = 4;
 
 #[async_trait::async_trait]
 impl RequestHandler for Handler {
@@ -25,84 +43,852 @@ impl RequestHandler for Handler {
         mut response: R
     )
     -> ResponseInfo {
-        match self.do_handle_request(request, response.clone()).await {
+        if self.draining.load(Ordering::Relaxed) {
+            return self.turn_away(request, response).await
+        }
+
+        let _inflight = match InflightGuard::acquire(&self.inflight, self.config.load().max_inflight) {
+            Some(guard) => guard,
+            None => {
+                self.stats.overloaded.fetch_add(1, Ordering::Relaxed);
+                let info = self.turn_away(request, response).await;
+                self.stats.response_codes.observe(info.response_code());
+                return info
+            }
+        };
+
+        let info = match self.do_handle_request(request, response.clone()).instrument(telemetry_mod::query_span(request)).instrument(crate::daemon_span()).await {
             Ok(info) => info,
+            // Every error, upstream outages included, is answered with a SERVFAIL so that clients do not wait for a timeout
             Err(error) => {
                 error!("Request n°{}: RequestHandler error: {}", request.id(), error);
 
                 let builder = MessageResponseBuilder::from_message_request(request);
                 let mut header = Header::response_from_request(request.header());
+                header.set_recursion_available(true);
                 header.set_response_code(ResponseCode::ServFail);
                 let message = builder.build(header, &[], &[], &[], &[]);
-                
-                response.send_response(message).await.expect("Could not send the ServFail")
+
+                match response.send_response(message).await {
+                    Ok(info) => info,
+                    Err(error) => {
+                        error!("Request n°{}: Could not send the SERVFAIL: {}", request.id(), error);
+                        ResponseInfo::from(header)
+                    }
+                }
+            }
+        };
+        self.stats.response_codes.observe(info.response_code());
+        info
+    }
+}
+
+// Queries being processed by every server, the last one to finish wakes up the drain
+#[derive(Default)]
+pub struct Inflight {
+    count: AtomicUsize,
+    idle: Notify
+}
+impl Inflight {
+    pub fn count (
+        &self
+    )
+    -> usize {
+        return self.count.load(Ordering::Relaxed)
+    }
+
+    // Returns the number of queries still in flight once they are all answered or the deadline is over
+    pub async fn wait_idle (
+        &self,
+        deadline: tokio::time::Instant
+    )
+    -> usize {
+        loop {
+            // Registered before the count is read, so a query finishing in between still wakes it up
+            let idle = self.idle.notified();
+            let count = self.count();
+            if count == 0 {
+                return 0
+            }
+            if tokio::time::timeout_at(deadline, idle).await.is_err() {
+                return self.count()
             }
         }
     }
 }
 
+// Holds one of the max_inflight slots until the request is answered
+struct InflightGuard<'a>(&'a Inflight);
+impl<'a> InflightGuard<'a> {
+    fn acquire (
+        inflight: &'a Inflight,
+        max_inflight: Option<usize>
+    )
+    -> Option<InflightGuard<'a>> {
+        let previous = inflight.count.fetch_add(1, Ordering::Relaxed);
+        let guard = InflightGuard(inflight);
+        return match max_inflight {
+            Some(max_inflight) if previous >= max_inflight => None,
+            _ => Some(guard)
+        }
+    }
+}
+impl Drop for InflightGuard<'_> {
+    fn drop (&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.0.idle.notify_waiters()
+        }
+    }
+}
+
 pub struct Handler {
-    pub redis_manager: redis::aio::ConnectionManager,
+    pub backend: Arc<dyn Backend>,
+    // Only set with the Redis backend, the static records and the query log live in Redis
+    pub redis_pool: Option<redis_mod::RedisPool>,
     pub config: Arc<ArcSwap<Config>>,
-    pub resolver: AsyncResolver<GenericConnection, GenericConnectionProvider<TokioRuntime>>
+    pub resolver: Arc<ArcSwap<resolver_mod::ForwarderPool>>,
+    pub stats: Arc<Stats>,
+    pub rate_limiter: Arc<RateLimiter>,
+    // Set for the binds given a "#policy" suffix, their queries are filtered with that policy's matchclasses
+    pub policy: Option<String>,
+    pub blackhole_rotation: AtomicUsize,
+    // Queries being processed by every server, checked against max_inflight
+    pub inflight: Arc<Inflight>,
+    pub response_rate_limiter: Arc<ResponseRateLimiter>,
+    pub match_cache: Arc<MatchCache>,
+    pub coalescer: Arc<Coalescer>,
+    pub policy_service: Arc<PolicyServiceClient>,
+    // Set once the shutdown started, new queries are turned away while the ones in flight finish
+    pub draining: Arc<AtomicBool>
 }
 impl Handler {
+    // UDP queries are dropped so that a flood gets no answers, TCP clients are told to come back later
+    async fn turn_away <R: ResponseHandler> (
+        &self,
+        request: &Request,
+        mut response: R
+    )
+    -> ResponseInfo {
+        let mut header = Header::response_from_request(request.header());
+        header.set_response_code(ResponseCode::Refused);
+        if matches!(request.protocol(), Protocol::Udp) {
+            return ResponseInfo::from(header)
+        }
+
+        let builder = MessageResponseBuilder::from_message_request(request);
+        let message = builder.build(header, &[], &[], &[], &[]);
+        return match response.send_response(message).await {
+            Ok(info) => info,
+            Err(_) => ResponseInfo::from(header)
+        }
+    }
+
+    async fn forward (
+        &self,
+        request: &Request,
+        header: Header
+    )
+    -> DnsLrResult<(Vec<Record>, Header)> {
+        self.stats.forwarded.fetch_add(1, Ordering::Relaxed);
+
+        let start = Instant::now();
+        let config = self.config.load();
+        // A name under a forward zone only ever reaches the forwarders of that zone
+        let name = request.query().name().into();
+        let forwarder_pool = self.resolver.load_full();
+        let (resolver, first_forwarder, is_zoned) = match forwarder_pool.pick_zone(&name) {
+            Some((resolver, first_forwarder)) => (resolver, Some(first_forwarder), true),
+            None => (forwarder_pool.pick(), None, false)
+        };
+        let mut answered_by = None;
+        let result = match (config.ecs, request.query().query_type()) {
+            // ECS queries are sent to the default forwarders, starting at the one the forwarder strategy picks
+            (Some(prefixes), RecordType::A | RecordType::AAAA | RecordType::TXT | RecordType::SRV | RecordType::MX) if !is_zoned => {
+                let forwarders = forwarder_pool.ordered_forwarders();
+                match resolver_mod::get_answers_with_subnet(request, header, &config, &forwarders, prefixes).await {
+                    Ok(None) => resolver_mod::get_answers(request, header, resolver).await,
+                    Ok(Some((answers, header, forwarder))) => {
+                        answered_by = Some(forwarder);
+                        Ok((answers, header))
+                    },
+                    Err(error) => Err(error)
+                }
+            },
+            _ => self.get_answers_coalesced(request, header, resolver).await
+        };
+        let elapsed = start.elapsed();
+        self.stats.resolver_latency.observe(elapsed);
+        let upstream = answered_by.map(|forwarder| forwarder.to_string()).or_else(|| first_forwarder.as_ref().map(ToString::to_string));
+        if let Some(upstream) = &upstream {
+            Span::current().record("upstream", upstream.as_str());
+        }
+        // The resolver does not say which forwarder answered, only the one it tried first is known
+        debug!("{}: Request n°{}: {} was forwarded to {} in {}ms", config.daemon_id, request.id(), request.query().name(),
+            match (answered_by, first_forwarder) {
+                (Some(forwarder), _) => forwarder.to_string(),
+                (None, Some(first_forwarder)) => format!("the resolver starting at {}", first_forwarder),
+                (None, None) => "the resolver".to_string()
+            },
+            elapsed.as_millis()
+        );
+
+        let result = result.map(|(mut answers, header)| {
+            if config.min_ttl.is_some() || config.max_ttl.is_some() {
+                for answer in answers.iter_mut() {
+                    let ttl = answer.ttl().max(config.min_ttl.unwrap_or(0)).min(config.max_ttl.unwrap_or(u32::MAX));
+                    answer.set_ttl(ttl);
+                }
+            }
+            (answers, header)
+        });
+
+        // Timeouts, refused connections and malformed answers all surface as one of these errors
+        // A SERVFAIL from the forwarders is passed on as is but still counts as a failed forward
+        match &result {
+            Err(WrappedErrors::ResolverError(_) | WrappedErrors::IOError(_) | WrappedErrors::ProtoError(_) | WrappedErrors::DNSlrError(ErrorKind::RequestRefused)) => {
+                self.stats.forward_errors.fetch_add(1, Ordering::Relaxed);
+            },
+            Ok((_, header)) if header.response_code() == ResponseCode::ServFail => {
+                self.stats.forward_errors.fetch_add(1, Ordering::Relaxed);
+            },
+            _ => ()
+        }
+        return result
+    }
+
+    // Only the first of several identical queries is forwarded, the others get its answer under their own header
+    async fn get_answers_coalesced (
+        &self,
+        request: &Request,
+        mut header: Header,
+        resolver: resolver_mod::Resolver
+    )
+    -> DnsLrResult<(Vec<Record>, Header)> {
+        match self.coalescer.join(coalesce_key(request)) {
+            Role::Leader(leader) => {
+                let result = resolver_mod::get_answers(request, header, resolver).await;
+                if let Ok((answers, header)) = &result {
+                    leader.share((answers.clone(), header.response_code()))
+                }
+                return result
+            },
+            Role::Follower(mut receiver) => {
+                if let Ok((answers, response_code)) = receiver.recv().await {
+                    self.stats.coalesced.fetch_add(1, Ordering::Relaxed);
+                    header.set_response_code(response_code);
+                    return Ok((answers, header))
+                }
+                return resolver_mod::get_answers(request, header, resolver).await
+            }
+        }
+    }
+
+    // Static records are answered locally, anything else is forwarded
+    async fn resolve (
+        &self,
+        request: &Request,
+        config: &Config,
+        header: Header
+    )
+    -> DnsLrResult<(Vec<Record>, Header)> {
+        if let (true, Some(redis_pool)) = (config.static_records, &self.redis_pool) {
+            match static_mod::lookup(request, config, redis_pool.get()).await {
+                Err(error) => {
+                    self.stats.redis_errors.fetch_add(1, Ordering::Relaxed);
+                    if let Some(failed) = self.redis_failure(request, config, header, &error) {
+                        return Ok(failed)
+                    }
+                },
+                Ok(Some(answers)) => {
+                    self.stats.static_answers.fetch_add(1, Ordering::Relaxed);
+                    debug!("{}: Request n°{}: {} was answered locally with no upstream", config.daemon_id, request.id(), request.query().name());
+                    return Ok((answers, matching::unsigned(header)))
+                },
+                Ok(None) => ()
+            }
+        }
+        // ANY queries are left to any_queries
+        let qtype = request.query().query_type();
+        if config.unsupported_qtypes_hinfo && qtype != RecordType::ANY && !resolver_mod::RESOLVED_QTYPES.contains(&qtype) {
+            debug!("{}: Request n°{}: {} of qtype {} was answered with HINFO with no upstream", config.daemon_id, request.id(), request.query().name(), qtype);
+            return Ok((vec![matching::any_hinfo(request)], matching::unsigned(header)))
+        }
+        return self.forward(request, header).instrument(telemetry_mod::forward_span()).await
+    }
+
+    // The policy service is only asked when no local matchclass decided, allow ones included
+    async fn find_matchclass (
+        &self,
+        request: &Request,
+        config: &Config,
+        matchclasses: &[Matchclass]
+    )
+    -> DnsLrResult<Option<Matchclass>> {
+        let matchclass = self.find_local_matchclass(request, config, matchclasses).await?;
+        let (None, Some(policy_service)) = (&matchclass, &config.policy_service) else {
+            return Ok(matchclass)
+        };
+
+        // Normalized like the names of the matchclasses, so every casing shares a decision and the service sees one form
+        let name = matching::query_name(request);
+        return match self.policy_service.decide(policy_service, request.src().ip(), &name, request.query().query_type()).await {
+            Ok(decision) => Ok(Some(matching::policy_service_matchclass(decision))),
+            Err(error) => {
+                self.stats.policy_service_errors.fetch_add(1, Ordering::Relaxed);
+                match policy_service.fail_open {
+                    true => {
+                        debug!("{}: Request n°{}: {} was forwarded unfiltered: {}", config.daemon_id, request.id(), name, error);
+                        Ok(None)
+                    },
+                    false => {
+                        debug!("{}: Request n°{}: {} was filtered: {}", config.daemon_id, request.id(), name, error);
+                        Ok(Some(matching::policy_service_matchclass(Decision::Block)))
+                    }
+                }
+            }
+        }
+    }
+
+    // Errors are never cached, the next query on the name tries Redis again
+    async fn find_local_matchclass (
+        &self,
+        request: &Request,
+        config: &Config,
+        matchclasses: &[Matchclass]
+    )
+    -> DnsLrResult<Option<Matchclass>> {
+        let Some((_, match_cache_ttl)) = config.match_cache else {
+            return matching::filter(request, config, matchclasses, self.backend.as_ref()).await
+        };
+
+        let key = match_cache_key(self.policy.clone(), request);
+        if let Some(matchclass) = self.match_cache.get(&key) {
+            self.stats.match_cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(matchclass)
+        }
+        self.stats.match_cache_misses.fetch_add(1, Ordering::Relaxed);
+
+        let matchclass = matching::filter(request, config, matchclasses, self.backend.as_ref()).await?;
+        self.match_cache.insert(key, matchclass.clone(), match_cache_ttl);
+        return Ok(matchclass)
+    }
+
+    // The answer to a query whose lookup could not reach Redis, None when it is to be forwarded unfiltered
+    fn redis_failure (
+        &self,
+        request: &Request,
+        config: &Config,
+        mut header: Header,
+        error: &WrappedErrors
+    )
+    -> Option<(Vec<Record>, Header)> {
+        return match config.redis_failure_mode {
+            RedisFailureMode::FailOpen => {
+                debug!("{}: Request n°{}: {} could not be looked up and was forwarded unfiltered: {}", config.daemon_id, request.id(), request.query().name(), error);
+                None
+            },
+            RedisFailureMode::FailClosed => {
+                debug!("{}: Request n°{}: {} could not be looked up and was answered with SERVFAIL: {}", config.daemon_id, request.id(), request.query().name(), error);
+                header.set_response_code(ResponseCode::ServFail);
+                Some((vec![], header))
+            }
+        }
+    }
+
+    fn log_query (
+        &self,
+        request: &Request,
+        config: &Config,
+        matchclass: &str
+    ) {
+        let Some(redis_pool) = &self.redis_pool else {
+            return
+        };
+        let mut redis_manager = redis_pool.get();
+        let daemon_id = config.daemon_id.clone();
+        let client = request.src().ip();
+        let name = request.query().name().to_string();
+        let matchclass = matchclass.to_string();
+
+        tokio::spawn(async move {
+            if let Err(error) = redis_mod::log_query(&mut redis_manager, &daemon_id, client, name, matchclass).await {
+                warn!("{}: Could not log the filtered query: {}", daemon_id, error)
+            }
+        }.in_current_span());
+    }
+
     async fn do_handle_request <R: ResponseHandler> (
         &self,
         request: &Request,
         mut response: R
     )
     -> DnsLrResult<ResponseInfo> {
-        if request.op_code() != OpCode::Query {
-            return Err(WrappedErrors::DNSlrError(ErrorKind::InvalidOpCode))
+        self.stats.queries.fetch_add(1, Ordering::Relaxed);
+        let query_span = Span::current();
+        let config = self.config.load();
+
+        // Dropped without an answer, answering a response could start a loop with the server that sent it
+        if let Some(reason) = malformed(request) {
+            let dropped = self.stats.malformed_dropped.fetch_add(1, Ordering::Relaxed);
+            if config.malformed_log_sample > 0 && dropped.is_multiple_of(config.malformed_log_sample) {
+                debug!("{}: Request n°{}: Dropped a malformed query from {}: {}", config.daemon_id, request.id(), request.src().ip(), reason)
+            }
+            return Ok(ResponseInfo::from(Header::response_from_request(request.header())))
         }
 
-        if request.message_type() != MessageType::Query {
-            return Err(WrappedErrors::DNSlrError(ErrorKind::InvalidMessageType))
+        if request.op_code() != OpCode::Query {
+            return Err(WrappedErrors::DNSlrError(ErrorKind::InvalidOpCode(request.op_code())))
         }
 
-        let builder = MessageResponseBuilder::from_message_request(request);
+        let mut builder = MessageResponseBuilder::from_message_request(request);
+        // EDNS clients are told how large a UDP answer they can get from us
+        let mut edns: Option<Edns> = None;
+        if let Some(request_edns) = request.edns() {
+            let mut response_edns = Edns::new();
+            response_edns.set_max_payload(resolver_mod::edns_payload_size());
+            response_edns.set_dnssec_ok(request_edns.dnssec_ok());
+            // RFC 7828 only lets the timeout go to the TCP and TLS clients that sent the option
+            if let (Some(tcp_keepalive), Protocol::Tcp | Protocol::Tls) = (config.tcp_keepalive, request.protocol()) {
+                if request_edns.options().as_ref().contains_key(&EdnsCode::Keepalive) {
+                    response_edns.options_mut().insert(resolver_mod::build_keepalive(tcp_keepalive));
+                }
+            }
+            builder.edns(response_edns.clone());
+            edns = Some(response_edns)
+        }
         let mut header = Header::response_from_request(request.header());
         header.set_authoritative(false);
         header.set_recursion_available(true);
 
-        let config = self.config.load();
+        let client = request.src().ip();
+        if !config.allowed_networks.is_empty() && !config.allowed_networks.iter().any(|network| network.contains(client)) {
+            self.stats.unauthorized.fetch_add(1, Ordering::Relaxed);
+
+            header.set_response_code(ResponseCode::Refused);
+            let message = builder.build(header, &[], &[], &[], &[]);
+            return match response.send_response(message).await {
+                Ok(ok) => Ok(ok),
+                Err(error) => Err(WrappedErrors::IOError(error))
+            }
+        }
+
+        if let Some((capacity, refill_per_sec)) = config.rate_limit {
+            if !self.rate_limiter.check(request.src().ip(), capacity, refill_per_sec) {
+                self.stats.rate_limited.fetch_add(1, Ordering::Relaxed);
+
+                header.set_response_code(ResponseCode::Refused);
+                let message = builder.build(header, &[], &[], &[], &[]);
+                return match response.send_response(message).await {
+                    Ok(ok) => Ok(ok),
+                    Err(error) => Err(WrappedErrors::IOError(error))
+                }
+            }
+        }
+
+        // The resolver only looks names up in the IN class, so CHAOS queries are never forwarded
+        if request.query().query_class() == DNSClass::CH {
+            let answers: Vec<Record> = matching::chaos_answer(request, &config).into_iter().collect();
+            if answers.is_empty() {
+                header.set_response_code(ResponseCode::Refused);
+            }
+            let message = builder.build(header, answers.iter(), &[], &[], &[]);
+            return match response.send_response(message).await {
+                Ok(ok) => Ok(ok),
+                Err(error) => Err(WrappedErrors::IOError(error))
+            }
+        }
+
+        // ANY queries are favored by amplification attacks, they can be kept from reaching the forwarders
+        if request.query().query_type() == RecordType::ANY && config.any_queries != AnyQueries::Pass {
+            let answers = match config.any_queries {
+                AnyQueries::Hinfo => vec![matching::any_hinfo(request)],
+                _ => {
+                    header.set_response_code(ResponseCode::Refused);
+                    vec![]
+                }
+            };
+            let message = builder.build(header, answers.iter(), &[], &[], &[]);
+            return match response.send_response(message).await {
+                Ok(ok) => Ok(ok),
+                Err(error) => Err(WrappedErrors::IOError(error))
+            }
+        }
+
+        let matchclasses = match &self.policy {
+            Some(policy) => config.policies.get(policy).map(Vec::as_slice),
+            None => config.matchclasses.as_deref()
+        };
 
-        let answers: Vec<Record>;
-        match config.is_filtering {
-            true => (answers, header) = match request.query().query_type() {
-                RecordType::A => matching::filter(
-                    request,
-                    header,
-                    config,
-                    self.redis_manager.clone(),
-                    self.resolver.clone()
-                ).await?,
-                RecordType::AAAA => matching::filter(
-                    request,
-                    header, 
-                    config,
-                    self.redis_manager.clone(),
-                    self.resolver.clone()
-                ).await?,
-                _ => resolver_mod::get_answers(
-                    request,
-                    header,
-                    self.resolver.clone()
-                ).await?
+        // Without allowed_networks only the local host may ask what is filtered
+        if let (true, Some(checked_name)) = (config.debug_queries, matching::debug_query_name(request)) {
+            if config.allowed_networks.is_empty() && !client.is_loopback() {
+                header.set_response_code(ResponseCode::Refused);
+                let message = builder.build(header, &[], &[], &[], &[]);
+                return match response.send_response(message).await {
+                    Ok(ok) => Ok(ok),
+                    Err(error) => Err(WrappedErrors::IOError(error))
+                }
+            }
+
+            let answers = match matching::debug_answer(request, &config, matchclasses.unwrap_or_default(), &checked_name, self.backend.as_ref()).await {
+                Ok(answer) => vec![answer],
+                Err(error) => {
+                    self.stats.redis_errors.fetch_add(1, Ordering::Relaxed);
+                    return Err(error)
+                }
+            };
+            debug!("{}: Request n°{}: answered the debug query on {}", config.daemon_id, request.id(), checked_name);
+            let message = builder.build(header, &answers, &[], &[], &[]);
+            return match response.send_response(message).await {
+                Ok(ok) => Ok(ok),
+                Err(error) => Err(WrappedErrors::IOError(error))
+            }
+        }
+
+        let mut answers: Vec<Record>;
+        let mut soa: Vec<Record> = vec![];
+        let mut filtered = false;
+        // Bypass clients are trusted, their queries skip every matchclass and go to the forwarders
+        let is_bypassed = config.bypass_networks.iter().any(|network| network.contains(client));
+        match (config.is_filtering && !is_bypassed, matchclasses) {
+            // Forwarding the reverse lookup of a blackhole IP would reveal that it is a lie
+            (true, _) if matching::is_blackhole_ptr(request, &config) => {
+                debug!("{}: Request n°{}: {} is a blackhole IP, answered with {}", config.daemon_id, request.id(), request.query().name(), config.blackhole_ptr);
+                answers = vec![matching::blackhole_ptr(request, &config)];
+                header = matching::unsigned(header)
             },
-            false => (answers, header) = resolver_mod::get_answers(
+            // Each matchclass decides which qtypes it filters, a query no matchclass covers costs no lookup
+            (true, matchclasses) if !matchclasses.unwrap_or_default().is_empty() || config.policy_service.is_some() => (answers, header) = match self.find_matchclass(
                 request,
-                header,
-                self.resolver.clone()
-            ).await?
+                &config,
+                matchclasses.unwrap_or_default()
+            ).instrument(telemetry_mod::match_lookup_span()).await {
+                Err(error) => {
+                    self.stats.redis_errors.fetch_add(1, Ordering::Relaxed);
+                    match self.redis_failure(request, &config, header, &error) {
+                        Some(failed) => failed,
+                        None => self.resolve(request, &config, header).await?
+                    }
+                },
+                Ok(Some(matchclass)) if matchclass.is_allow => self.resolve(request, &config, header).await?,
+                Ok(Some(matchclass)) => {
+                    self.stats.filtered.fetch_add(1, Ordering::Relaxed);
+                    self.stats.top_blocked.observe(&matching::query_name(request));
+                    filtered = true;
+                    debug!("{}: Request n°{}: {} was short-circuited by {} with no upstream", config.daemon_id, request.id(), request.query().name(), matchclass.name);
+                    if config.query_log {
+                        self.log_query(request, &config, &matchclass.name)
+                    }
+                    if matching::is_nxdomain_lie(request, &config, &matchclass) {
+                        soa.push(matching::negative_soa(request, &config))
+                    }
+                    // The option only fits in the OPT record of a client that sent one
+                    if let (Some((extended_error, text)), Some(edns)) = (&config.extended_error, edns.as_mut()) {
+                        let text = text.clone().unwrap_or_else(|| format!("blocked by {}", matchclass.name));
+                        edns.options_mut().insert(resolver_mod::build_extended_error(*extended_error, &text));
+                        builder.edns(edns.clone());
+                    }
+                    matching::lie(request, &config, header, &matchclass, self.blackhole_rotation.fetch_add(1, Ordering::Relaxed))
+                },
+                Ok(None) => self.resolve(request, &config, header).await?
+            },
+            _ => (answers, header) = self.resolve(request, &config, header).await?
         }
 
+        query_span.record("filtered", filtered);
 
-        let message = builder.build(header, answers.iter(), &[], &[], &[]);
-        return match response.send_response(message).await {
+        if header.response_code() == ResponseCode::NXDomain {
+            self.stats.nxdomains.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // The DNSSEC records and anything else the resolver collected on the way are left out
+        if config.minimal_responses {
+            let qtype = request.query().query_type();
+            // An RFC 8482 HINFO stands for the whole answer, whichever qtype was asked
+            answers.retain(|record| record.record_type() == qtype || record.record_type() == RecordType::CNAME || matching::is_rfc8482_hinfo(record));
+            soa.clear()
+        }
+
+        // Only UDP answers can be reflected to a spoofed source, TCP ones are never limited
+        if let (Some(rrl), Protocol::Udp) = (config.rrl, request.protocol()) {
+            match self.response_rate_limiter.check(request.src().ip(), &header, answers.len(), rrl) {
+                Verdict::Send => (),
+                Verdict::Slip => {
+                    self.stats.rrl_limited.fetch_add(1, Ordering::Relaxed);
+
+                    header.set_truncated(true);
+                    let message = builder.build(header, &[], &[], &[], &[]);
+                    return match response.send_response(message).await {
+                        Ok(ok) => Ok(ok),
+                        Err(error) => Err(WrappedErrors::IOError(error))
+                    }
+                },
+                Verdict::Drop => {
+                    self.stats.rrl_limited.fetch_add(1, Ordering::Relaxed);
+                    return Ok(ResponseInfo::from(header))
+                }
+            }
+        }
+
+        if fit_udp(request, &mut header, &mut answers, &mut soa, edns.as_ref()) {
+            debug!("{}: Request n°{}: The answer to {} does not fit in UDP, sent truncated", config.daemon_id, request.id(), request.query().name());
+        }
+
+        let message = builder.build(header, answers.iter(), &[], soa.iter(), &[]);
+        return match response.send_response(message).instrument(telemetry_mod::respond_span()).await {
             Ok(ok) => Ok(ok),
             Err(error) => Err(WrappedErrors::IOError(error))
         }
     }
 }
+
+// Every casing of a name shares the entry of its normalized form, the one find_match looks up
+fn match_cache_key (
+    policy: Option<String>,
+    request: &Request
+)
+-> match_cache_mod::Key {
+    return (policy, matching::query_name(request), request.query().query_type())
+}
+
+fn coalesce_key (
+    request: &Request
+)
+-> coalesce_mod::Key {
+    return (matching::query_name(request), request.query().query_type(), request.query().query_class())
+}
+
+// Queries trust-dns could decode but that no resolver sends, the reason they are dropped
+fn malformed (
+    request: &Request
+)
+-> Option<&'static str> {
+    if request.message_type() != MessageType::Query {
+        return Some("the message is a response")
+    }
+    if request.truncated() {
+        return Some("the query is truncated")
+    }
+    if !request.answers().is_empty() || !request.name_servers().is_empty() {
+        return Some("the query carries answer or authority records")
+    }
+    if !request.additionals().is_empty() {
+        return Some("the query carries additional records besides the OPT one")
+    }
+    if query_size(request) > MAX_QUERY_SIZE {
+        return Some("the query is oversized")
+    }
+    return None
+}
+
+// The size the query had on the wire, added up from its decoded question and OPT record instead of encoding it again
+// Names are counted uncompressed, the only other records were rejected by malformed first
+fn query_size (
+    request: &Request
+)
+-> usize {
+    let mut size = HEADER_SIZE + request.query().original().name().len() + 1 + QUESTION_FIXED_SIZE;
+    if let Some(edns) = request.edns() {
+        size += OPT_FIXED_SIZE + edns.options().as_ref().values().map(|option| EDNS_OPTION_FIXED_SIZE + option.len() as usize).sum::<usize>()
+    }
+    return size
+}
+
+// trust-dns sends a UDP answer whatever its size, past the client's limit it would be dropped on the way
+// An answer over the limit is sent empty with TC set so the client asks again over TCP, true when it was
+fn fit_udp (
+    request: &Request,
+    header: &mut Header,
+    answers: &mut Vec<Record>,
+    soa: &mut Vec<Record>,
+    edns: Option<&Edns>
+)
+-> bool {
+    if !matches!(request.protocol(), Protocol::Udp) || response_size(request, *header, answers, soa, edns) <= resolver_mod::udp_payload_limit(request) {
+        return false
+    }
+    header.set_truncated(true);
+    answers.clear();
+    soa.clear();
+    return true
+}
+
+// The size of the response once encoded, edns is the OPT record given to the response builder
+fn response_size (
+    request: &Request,
+    header: Header,
+    answers: &[Record],
+    soa: &[Record],
+    edns: Option<&Edns>
+)
+-> usize {
+    let mut message = Message::new();
+    message.set_header(header);
+    message.add_query(request.query().original().clone());
+    message.insert_answers(answers.to_vec());
+    message.insert_name_servers(soa.to_vec());
+    if let Some(edns) = edns {
+        message.set_edns(edns.clone());
+    }
+    // A response that cannot be encoded fails when sent anyway, it is not truncated here
+    return message.to_vec().map_or(0, |encoded| encoded.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use trust_dns_proto::{
+        op::Query,
+        rr::{Name, RData, rdata::{SOA, opt::EdnsOption}},
+        serialize::binary::BinDecodable
+    };
+    use trust_dns_server::authority::MessageRequest;
+
+    fn request (
+        protocol: Protocol
+    )
+    -> Request {
+        return named_request("big.example.com.", protocol)
+    }
+
+    fn named_request (
+        name: &str,
+        protocol: Protocol
+    )
+    -> Request {
+        let mut message = Message::new();
+        message.set_id(80);
+        message.set_recursion_desired(true);
+        message.add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::A));
+        let message = MessageRequest::from_bytes(&message.to_vec().unwrap()).unwrap();
+        return Request::new(message, ([127, 0, 0, 1], 5353).into(), protocol)
+    }
+
+    #[test]
+    fn match_cache_key_ignores_the_case_of_the_name () {
+        let match_cache = MatchCache::new(16);
+        match_cache.insert(match_cache_key(None, &named_request("example.com.", Protocol::Udp)), None, std::time::Duration::from_secs(60));
+        assert_eq!(match_cache.get(&match_cache_key(None, &named_request("Example.COM.", Protocol::Udp))).map(|matchclass| matchclass.is_none()), Some(true));
+        assert!(match_cache.get(&match_cache_key(Some("kids".to_string()), &named_request("Example.COM.", Protocol::Udp))).is_none());
+    }
+
+    #[test]
+    fn coalesce_key_ignores_the_case_of_the_name () {
+        let coalescer = Coalescer::default();
+        let _leader = coalescer.join(coalesce_key(&named_request("example.com.", Protocol::Udp)));
+        assert!(matches!(coalescer.join(coalesce_key(&named_request("Example.COM", Protocol::Udp))), Role::Follower(_)));
+    }
+
+    // A query with an OPT record carrying an option of option_size bytes and the extra records given
+    fn edns_request (
+        option_size: usize,
+        additionals: Vec<Record>
+    )
+    -> Request {
+        let mut message = Message::new();
+        message.set_id(90);
+        message.add_query(Query::query(Name::from_ascii("www.example.com.").unwrap(), RecordType::A));
+        let mut edns = Edns::new();
+        edns.options_mut().insert(EdnsOption::Unknown(65001, vec![0; option_size]));
+        message.set_edns(edns);
+        message.insert_additionals(additionals);
+        let encoded = message.to_vec().unwrap();
+        let message = MessageRequest::from_bytes(&encoded).unwrap();
+        let request = Request::new(message, ([127, 0, 0, 1], 5353).into(), Protocol::Udp);
+        // The added up size matches the bytes the query came in, as long as nothing but the OPT record follows the question
+        if request.additionals().is_empty() {
+            assert_eq!(query_size(&request), encoded.len());
+        }
+        return request
+    }
+
+    #[test]
+    fn malformed_drops_an_oversized_query () {
+        assert_eq!(malformed(&edns_request(100, vec![])), None);
+        assert_eq!(malformed(&edns_request(MAX_QUERY_SIZE, vec![])), Some("the query is oversized"));
+    }
+
+    #[test]
+    fn malformed_drops_a_query_with_additional_records () {
+        let record = Record::from_rdata(Name::from_ascii("www.example.com.").unwrap(), 60, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        assert_eq!(malformed(&edns_request(0, vec![record])), Some("the query carries additional records besides the OPT one"));
+    }
+
+    // Far more A records than 512 bytes hold, with the SOA of a negative answer
+    fn big_answer ()
+    -> (Vec<Record>, Vec<Record>) {
+        let name = Name::from_ascii("big.example.com.").unwrap();
+        let answers = (0..100u8)
+            .map(|index| Record::from_rdata(name.clone(), 60, RData::A(Ipv4Addr::new(192, 0, 2, index))))
+            .collect();
+        let soa = SOA::new(Name::from_ascii("example.com.").unwrap(), Name::from_ascii("hostmaster.example.com.").unwrap(), 1, 3600, 600, 86400, 60);
+        return (answers, vec![Record::from_rdata(Name::from_ascii("example.com.").unwrap(), 60, RData::SOA(soa))])
+    }
+
+    #[test]
+    fn fit_udp_truncates_an_answer_over_the_limit () {
+        let request = request(Protocol::Udp);
+        let mut header = Header::response_from_request(request.header());
+        let (mut answers, mut soa) = big_answer();
+        assert!(response_size(&request, header, &answers, &soa, None) > resolver_mod::udp_payload_limit(&request));
+
+        assert!(fit_udp(&request, &mut header, &mut answers, &mut soa, None));
+        assert!(header.truncated());
+        assert!(answers.is_empty());
+        assert!(soa.is_empty());
+        assert!(response_size(&request, header, &answers, &soa, None) <= resolver_mod::udp_payload_limit(&request));
+    }
+
+    #[test]
+    fn fit_udp_keeps_an_answer_within_the_limit () {
+        let request = request(Protocol::Udp);
+        let mut header = Header::response_from_request(request.header());
+        let (mut answers, mut soa) = big_answer();
+        answers.truncate(2);
+
+        assert!(!fit_udp(&request, &mut header, &mut answers, &mut soa, None));
+        assert!(!header.truncated());
+        assert_eq!(answers.len(), 2);
+        assert_eq!(soa.len(), 1);
+    }
+
+    #[test]
+    fn fit_udp_leaves_tcp_answers_untouched () {
+        let request = request(Protocol::Tcp);
+        let mut header = Header::response_from_request(request.header());
+        let (mut answers, mut soa) = big_answer();
+
+        assert!(!fit_udp(&request, &mut header, &mut answers, &mut soa, None));
+        assert!(!header.truncated());
+        assert_eq!(answers.len(), 100);
+        assert_eq!(soa.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn wait_idle_wakes_up_when_the_last_query_is_answered () {
+        let inflight = Arc::new(Inflight::default());
+        let started = tokio::time::Instant::now();
+        let answering = Arc::clone(&inflight);
+        let (acquired, is_acquired) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let _first = InflightGuard::acquire(&answering, None).unwrap();
+            let _second = InflightGuard::acquire(&answering, None).unwrap();
+            acquired.send(()).unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await
+        });
+        is_acquired.await.unwrap();
+
+        assert_eq!(inflight.wait_idle(started + std::time::Duration::from_secs(5)).await, 0);
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn wait_idle_gives_up_at_the_deadline () {
+        let inflight = Inflight::default();
+        let _guard = InflightGuard::acquire(&inflight, None).unwrap();
+        assert_eq!(inflight.wait_idle(tokio::time::Instant::now() + std::time::Duration::from_millis(20)).await, 1);
+        assert_eq!(InflightGuard::acquire(&inflight, Some(1)).map(|_| ()), None);
+        assert_eq!(inflight.count(), 1);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::resolver_mod::ForwarderPool;
+
+use redis::aio::ConnectionManager;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+    time::timeout
+};
+use tracing::{debug, Instrument};
+use std::{
+    sync::Arc,
+    time::Duration
+};
+use arc_swap::ArcSwap;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub async fn serve (
+    listener: TcpListener,
+    redis_manager: Option<ConnectionManager>,
+    arc_resolver: Arc<ArcSwap<ForwarderPool>>,
+    daemon_id: String
+) {
+    loop {
+        let Ok((mut stream, peer)) = listener.accept().await else {
+            continue
+        };
+        let redis_manager = redis_manager.clone();
+        let forwarder_pool = arc_resolver.load_full();
+        let daemon_id = daemon_id.clone();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            let Ok(read) = stream.read(&mut buffer).await else {
+                return
+            };
+
+            let request = String::from_utf8_lossy(&buffer[..read]);
+            let (status, body) = match request.split_whitespace().nth(1) {
+                Some("/healthz") => ("200 OK", "ok\n"),
+                Some("/readyz") => match is_ready(redis_manager, &forwarder_pool, &daemon_id).await {
+                    true => ("200 OK", "ready\n"),
+                    false => ("503 Service Unavailable", "not ready\n")
+                },
+                _ => ("404 Not Found", "")
+            };
+            let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                debug!("{}: Could not answer the health request from {}", daemon_id, peer)
+            }
+        }.in_current_span());
+    }
+}
+
+// Ready means Redis answers a PING, when it is the backend, and a forwarder answers a root NS query
+// The query goes to the forwarders themselves, the resolver would answer it from its cache for days
+async fn is_ready (
+    redis_manager: Option<ConnectionManager>,
+    forwarder_pool: &ForwarderPool,
+    daemon_id: &str
+)
+-> bool {
+    if let Some(mut redis_manager) = redis_manager {
+        let ping = redis::cmd("PING");
+        if !matches!(timeout(PROBE_TIMEOUT, ping.query_async::<_, String>(&mut redis_manager)).await, Ok(Ok(_))) {
+            debug!("{}: Readiness probe: Redis did not answer", daemon_id);
+            return false
+        }
+    }
+
+    if !matches!(timeout(PROBE_TIMEOUT, forwarder_pool.any_forwarder_answers()).await, Ok(true)) {
+        debug!("{}: Readiness probe: no forwarder answered", daemon_id);
+        return false
+    }
+    return true
+}
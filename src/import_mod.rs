@@ -0,0 +1,95 @@
+use crate::enums_structs::DnsLrResult;
+use crate::redis_mod;
+use crate::matching;
+
+use redis::aio::ConnectionManager;
+use tracing::{info, warn};
+use std::{
+    fs,
+    net::IpAddr
+};
+
+// Hosts files map these to loopback, they are not blocklist entries
+const LOCAL_NAMES: [&str; 6] = ["localhost", "localhost.localdomain", "local", "broadcasthost", "ip6-localhost", "ip6-loopback"];
+
+#[derive(Debug, Default)]
+pub struct HostsSummary {
+    pub domains: Vec<String>,
+    pub skipped: u32,
+    pub malformed: u32
+}
+
+// Parses "0.0.0.0 domain" and "127.0.0.1 domain" lines, comments and blank lines are skipped
+pub fn parse_hosts (
+    contents: &str
+)
+-> HostsSummary {
+    let mut summary = HostsSummary::default();
+
+    for line in contents.lines() {
+        let line = match line.split_once('#') {
+            Some((line, _)) => line,
+            None => line
+        };
+        let mut fields = line.split_whitespace();
+        let Some(address) = fields.next() else {
+            summary.skipped += 1;
+            continue
+        };
+
+        let Ok(ip) = address.parse::<IpAddr>() else {
+            summary.malformed += 1;
+            continue
+        };
+        if !ip.is_unspecified() && !ip.is_loopback() {
+            summary.malformed += 1;
+            continue
+        }
+
+        let mut has_domain = false;
+        for domain in fields {
+            has_domain = true;
+            if domain.parse::<IpAddr>().is_ok() {
+                summary.skipped += 1;
+                continue
+            }
+            // Stored in the form the queries are matched with
+            let Some(domain) = matching::normalize_name(domain) else {
+                summary.malformed += 1;
+                continue
+            };
+            if LOCAL_NAMES.contains(&domain.trim_end_matches('.')) {
+                summary.skipped += 1;
+                continue
+            }
+            summary.domains.push(domain)
+        }
+        if !has_domain {
+            summary.malformed += 1
+        }
+    }
+
+    summary.domains.sort_unstable();
+    summary.domains.dedup();
+    return summary
+}
+
+pub async fn import_hosts (
+    manager: &mut ConnectionManager,
+    path: &str,
+    matchclass: &str,
+    daemon_id: &String
+)
+-> DnsLrResult<()> {
+    let contents = fs::read_to_string(path)?;
+    let summary = parse_hosts(&contents);
+
+    redis_mod::store_matches(manager, matchclass, &summary.domains).await?;
+    info!("{}: Imported {} domains into {} from {}, skipped {} lines and {} malformed entries", daemon_id, summary.domains.len(), matchclass, path, summary.skipped, summary.malformed);
+
+    let matchclasses = redis_mod::hkeys(manager, "matchclasses", daemon_id).await?;
+    if !matchclasses.iter().any(|name| name == matchclass) {
+        warn!("{}: {} is not listed in matchclasses_{}, its domains will not be filtered", daemon_id, matchclass, daemon_id)
+    }
+    return Ok(())
+}
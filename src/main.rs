@@ -1,156 +1,986 @@
+#![allow(clippy::needless_return, clippy::enum_variant_names)]
+
 mod handler_mod;
 mod redis_mod;
 mod resolver_mod;
 mod matching;
 mod enums_structs;
+mod metrics_mod;
+mod health_mod;
+mod rate_limit_mod;
+mod rrl_mod;
+mod socket_mod;
+mod static_mod;
+mod import_mod;
+mod blocklist_mod;
+mod telemetry_mod;
+mod match_cache_mod;
+mod coalesce_mod;
+mod backend_mod;
+mod top_blocked_mod;
+mod policy_service_mod;
 
-use crate::handler_mod::Handler;
-use crate::enums_structs::{Config, DnsLrResult, WrappedErrors, ErrorKind, Confile};
+use crate::handler_mod::{Handler, Inflight};
+use crate::rate_limit_mod::RateLimiter;
+use crate::rrl_mod::ResponseRateLimiter;
+use crate::blocklist_mod::BlocklistFetcher;
+use crate::match_cache_mod::MatchCache;
+use crate::coalesce_mod::Coalescer;
+use crate::policy_service_mod::PolicyServiceClient;
+use crate::resolver_mod::ForwarderPool;
+use crate::redis_mod::RedisPool;
+use crate::backend_mod::{Backend, FileBackend};
+use crate::enums_structs::{Config, DnsLrResult, WrappedErrors, ErrorKind, Confile, Forwarder, Stats, Command};
 
 use arc_swap::ArcSwap;
 use trust_dns_server::ServerFuture;
 
 use tokio::{
-    net::{TcpListener, UdpSocket}
+    net::TcpListener,
+    sync::Notify
 };
 use std::{
-    time::Duration,
+    collections::{HashMap, HashSet},
+    env,
     fs,
-    sync::Arc
+    net::SocketAddr,
+    io::{self, Write},
+    ops::Deref,
+    path::Path,
+    process::ExitCode,
+    sync::{Arc, OnceLock, atomic::{AtomicBool, AtomicUsize, Ordering}}
 };
-use tracing::{info, error, warn};
+use rustls::{Certificate, PrivateKey};
+use trust_dns_proto::rustls::tls_server;
+use trust_dns_proto::rr::{Name, RecordType};
+use tracing::{info, error, warn, debug, error_span, Instrument, Span};
 use signal_hook_tokio::Signals;
-use signal_hook::consts::signal::{SIGHUP, SIGUSR1, SIGUSR2};
+use sd_notify::NotifyState;
+use signal_hook::consts::signal::{SIGHUP, SIGUSR1, SIGUSR2, SIGTERM, SIGINT};
 use futures_util::{
+    future,
     stream::StreamExt
 };
-use lazy_static::lazy_static;
+use tracing_subscriber::{EnvFilter, fmt, prelude::*};
+
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_CONFILE: &str = "dnslr.conf";
 
-const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+// Loaded once at the start of main, before anything reads it
+struct ConfileCell(OnceLock<Confile>);
+impl Deref for ConfileCell {
+    type Target = Confile;
 
-lazy_static! {
-    static ref CONFILE: Confile = read_confile("dnslr.conf");
+    fn deref (&self) -> &Confile {
+        return self.0.get().expect("The config file is read at startup")
+    }
 }
 
+static CONFILE: ConfileCell = ConfileCell(OnceLock::new());
+
 fn read_confile (
     file_name: &str
 )
--> Confile {
-    let confile: Confile = {
-        let data = fs::read_to_string(file_name).expect("Error reading config file");
-        serde_json::from_str(&data).expect("Error deserializing config file data")
+-> DnsLrResult<Confile> {
+    let data = match fs::read_to_string(file_name) {
+        Ok(ok) => ok,
+        Err(error) => return Err(WrappedErrors::DNSlrError(ErrorKind::ConfileNotReadable {path: file_name.to_string(), source: error}))
     };
 
-    info!("Daemon_id is {}", confile.daemon_id);
-    info!("{}: Redis server: {}", confile.daemon_id, confile.redis_address);
-    
-    return confile
+    let mut confile = match serde_json::from_str::<Confile>(&data) {
+        Ok(ok) => ok,
+        Err(error) => return Err(WrappedErrors::DNSlrError(ErrorKind::ConfileInvalid {path: file_name.to_string(), reason: error.to_string()}))
+    };
+
+    if !matches!(confile.backend.as_deref(), None | Some("redis") | Some("file")) {
+        return Err(WrappedErrors::DNSlrError(ErrorKind::ConfileInvalid {
+            path: file_name.to_string(),
+            reason: format!("backend: {} is not redis or file", confile.backend.clone().unwrap_or_default())
+        }))
+    }
+    if confile.is_file_backend() && confile.backend_file.is_none() {
+        confile.backend_file = Some(file_name.to_string())
+    }
+
+    if !matches!(confile.runtime_flavor.as_deref(), None | Some("multi_thread") | Some("current_thread")) {
+        return Err(WrappedErrors::DNSlrError(ErrorKind::ConfileInvalid {
+            path: file_name.to_string(),
+            reason: format!("runtime_flavor: {} is not multi_thread or current_thread", confile.runtime_flavor.clone().unwrap_or_default())
+        }))
+    }
+    // Tokio panics on a runtime without workers
+    if confile.worker_threads == Some(0) {
+        return Err(WrappedErrors::DNSlrError(ErrorKind::ConfileInvalid {
+            path: file_name.to_string(),
+            reason: "worker_threads must be at least 1".to_string()
+        }))
+    }
+
+    // redis 0.22 builds its TLS connector with the system roots and takes no other CA
+    if let Some(redis_ca_cert) = &confile.redis_ca_cert {
+        return Err(WrappedErrors::DNSlrError(ErrorKind::RedisCaCertUnsupported(redis_ca_cert.clone())))
+    }
+
+    if let Some(edns_payload_size) = confile.edns_payload_size {
+        if !(resolver_mod::MIN_EDNS_PAYLOAD_SIZE..=resolver_mod::MAX_EDNS_PAYLOAD_SIZE).contains(&edns_payload_size) {
+            return Err(WrappedErrors::DNSlrError(ErrorKind::ConfileInvalid {
+                path: file_name.to_string(),
+                reason: format!("edns_payload_size: {} is not between {} and {}", edns_payload_size, resolver_mod::MIN_EDNS_PAYLOAD_SIZE, resolver_mod::MAX_EDNS_PAYLOAD_SIZE)
+            }))
+        }
+    }
+
+    return Ok(confile)
 }
 
-async fn setup_binds (
+// Carries daemon_id as a structured field on every event, the JSON formatter prints it
+// The span is at the error level so that no log level filters it out
+pub fn daemon_span ()
+-> Span {
+    return error_span!("daemon", daemon_id = %CONFILE.daemon_id)
+}
+
+fn init_logging () {
+    // RUST_LOG wins over log_level so existing deployments keep their filters
+    let mut invalid_log_level = None;
+    let filter = match env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(_) => EnvFilter::from_default_env(),
+        Err(_) => match CONFILE.log_level.as_deref() {
+            Some(log_level @ ("trace" | "debug" | "info" | "warn" | "error")) => EnvFilter::new(log_level),
+            Some(log_level) => {
+                invalid_log_level = Some(log_level);
+                EnvFilter::new(DEFAULT_LOG_LEVEL)
+            },
+            None => EnvFilter::new(DEFAULT_LOG_LEVEL)
+        }
+    };
+
+    // The query spans are exported, never logged
+    let filter = filter.add_directive(format!("{}=off", telemetry_mod::TARGET).parse().expect("The telemetry target is a valid directive"));
+    let (telemetry_layer, telemetry_error) = match telemetry_mod::layer() {
+        Ok(telemetry_layer) => (telemetry_layer, None),
+        Err(error) => (None, Some(error))
+    };
+    let registry = tracing_subscriber::registry().with(telemetry_layer);
+
+    let log_format = env::var("DNSLR_LOG_FORMAT").ok().or_else(|| CONFILE.log_format.clone());
+    match log_format.as_deref() {
+        Some("json") => registry.with(fmt::layer().json().with_current_span(true).with_span_list(false).with_filter(filter)).init(),
+        Some("plain") | None => registry.with(fmt::layer().with_filter(filter)).init(),
+        Some(log_format) => {
+            registry.with(fmt::layer().with_filter(filter)).init();
+            warn!("log_format: {} is not valid, defaulting to plain", log_format)
+        }
+    }
+    if let Some(log_level) = invalid_log_level {
+        warn!("log_level: {} is not valid, defaulting to {}", log_level, DEFAULT_LOG_LEVEL)
+    }
+    match (&CONFILE.otlp_endpoint, telemetry_error) {
+        (Some(otlp_endpoint), Some(error)) => warn!("Could not set up the OTLP exporter to {}, queries will not be traced: {}", otlp_endpoint, error),
+        (Some(otlp_endpoint), None) => info!("Query traces are exported to {}", otlp_endpoint),
+        (None, _) => ()
+    }
+
+    let _span = daemon_span().entered();
+    info!("Daemon_id is {}", CONFILE.daemon_id);
+    match &CONFILE.backend_file {
+        Some(backend_file) if CONFILE.is_file_backend() => info!("{}: Backend file: {}", CONFILE.daemon_id, backend_file),
+        _ => info!("{}: Redis server: {}", CONFILE.daemon_id, CONFILE.redis_address)
+    }
+}
+
+fn read_tls_certificate_and_key ()
+-> DnsLrResult<(Vec<Certificate>, PrivateKey)> {
+    let (Some(certificate_path), Some(private_key_path)) = (&CONFILE.tls_certificate, &CONFILE.tls_private_key) else {
+        return Err(WrappedErrors::DNSlrError(ErrorKind::TlsNotConfigured))
+    };
+
+    let certificate = tls_server::read_cert(Path::new(certificate_path))
+        .map_err(|source| WrappedErrors::DNSlrError(ErrorKind::TlsMaterialUnreadable {path: certificate_path.clone(), source}))?;
+    let private_key = tls_server::read_key(Path::new(private_key_path))
+        .map_err(|source| WrappedErrors::DNSlrError(ErrorKind::TlsMaterialUnreadable {path: private_key_path.clone(), source}))?;
+
+    return Ok((certificate, private_key))
+}
+
+// Registers one bind on the server of its policy, address is the bind without its policy
+async fn setup_bind (
+    config: &Config,
     server: &mut ServerFuture<Handler>,
-    config: &Config
+    address: &str,
+    udp_addresses: &[SocketAddr]
 )
 -> DnsLrResult<()> {
-    let bind_count = config.binds.clone().iter().count() as u32;
-    let mut successful_binds_count: u32 = 0;
-    for bind in config.binds.clone().into_iter() {
-        let splits: Vec<&str> = bind.split("=").collect();
-
-        match splits[0] {
-            "UDP" => {
-                let Ok(socket) = UdpSocket::bind(splits[1]).await else {
-                    warn!("{}: Failed to bind: {}", config.daemon_id, bind);
-                    continue
-                };
-                server.register_socket(socket)
-            },
-            "TCP" => {
-                let Ok(listener) = TcpListener::bind(splits[1]).await else {
-                    warn!("{}: Failed to bind: {}", config.daemon_id, bind);
-                    continue
-                };
-                server.register_listener(listener, TCP_TIMEOUT)
-            },
+    let (protocol, address) = address.split_once('=').unwrap_or((address, ""));
+    match protocol {
+        "UDP" => {
+            let socket = socket_mod::bind_udp(address, config.reuseport, &config.daemon_id).await?;
+            server.register_socket(socket)
+        },
+        "TCP" => {
+            let listener = socket_mod::bind_tcp(address, config.reuseport, &config.daemon_id).await?;
+            server.register_listener(listener, config.tcp_timeout)
+        },
+        "TLS" => {
+            let certificate_and_key = read_tls_certificate_and_key()?;
+            let listener = socket_mod::bind_tcp(address, config.reuseport, &config.daemon_id).await?;
+            server.register_tls_listener(listener, config.tcp_timeout, certificate_and_key)?
+        },
+        "HTTPS" => {
+            let Some(https_hostname) = CONFILE.https_hostname.clone() else {
+                return Err(WrappedErrors::DNSlrError(ErrorKind::HttpsHostnameMissing))
+            };
+            let certificate_and_key = read_tls_certificate_and_key()?;
+            let listener = socket_mod::bind_tcp(address, config.reuseport, &config.daemon_id).await?;
+            server.register_https_listener(listener, config.tcp_timeout, certificate_and_key, https_hostname)?
+        },
+        "QUIC" => {
+            if let Ok(quic_address) = socket_mod::parse_address(socket_mod::split_device(address).0) {
+                if udp_addresses.iter().any(|udp_address| socket_mod::overlaps(*udp_address, quic_address)) {
+                    return Err(WrappedErrors::DNSlrError(ErrorKind::QuicPortConflict))
+                }
+            }
+            let certificate_and_key = read_tls_certificate_and_key()?;
+            let socket = socket_mod::bind_udp(address, config.reuseport, &config.daemon_id).await?;
+            let quic_hostname = CONFILE.https_hostname.clone().unwrap_or_default();
+            server.register_quic_listener(socket, config.tcp_timeout, certificate_and_key, quic_hostname)?
+        },
+        _ => return Err(WrappedErrors::DNSlrError(ErrorKind::UnknownBindProtocol(protocol.to_string())))
+    };
+    return Ok(())
+}
+
+// Binds sharing a policy share a server, its handler filters with the matchclasses of that policy
+async fn setup_binds (
+    config: &Config,
+    new_handler: impl Fn(Option<String>) -> Handler
+)
+-> DnsLrResult<Vec<ServerFuture<Handler>>> {
+    // The same protocol, address and device bound twice would fail as address in use, whatever the policy
+    let mut bound_addresses: HashSet<String> = HashSet::new();
+    let mut binds: Vec<String> = vec![];
+    for bind in config.binds.iter() {
+        // A bind is "{protocol}={address}", an entry without both parts is a typo that can never be bound
+        let (protocol, address) = match socket_mod::split_policy(bind).0.split_once('=') {
+            Some((protocol, address)) if !protocol.is_empty() && !address.is_empty() => (protocol, address),
             _ => {
-                warn!("{}: Failed to bind: {}", config.daemon_id, bind);
+                warn!("{}: Skipping the bind: {}, it is not in the PROTOCOL=address form", config.daemon_id, bind);
                 continue
             }
         };
-        successful_binds_count += 1
+        match protocol {
+            "UDP" if !config.enable_udp => {
+                info!("{}: Skipping the bind: {}, enable_udp is off", config.daemon_id, bind);
+                continue
+            },
+            "TCP" if !config.enable_tcp => {
+                info!("{}: Skipping the bind: {}, enable_tcp is off", config.daemon_id, bind);
+                continue
+            },
+            _ => ()
+        }
+        let (address, device) = socket_mod::split_device(address);
+        let address = socket_mod::parse_address(address).map_or(address.to_string(), |address| address.to_string());
+        let normalized_address = format!("{}={}%{}", protocol, address, device.unwrap_or_default());
+        if !bound_addresses.insert(normalized_address) {
+            warn!("{}: Dropping the duplicate bind: {}", config.daemon_id, bind);
+            continue
+        }
+        binds.push(bind.clone())
+    }
+    let bind_count = binds.len() as u32;
+
+    // QUIC runs over UDP, so it cannot share a port with a plain UDP bind
+    let udp_addresses: Vec<SocketAddr> = binds.iter()
+        .filter_map(|bind| socket_mod::split_policy(bind).0.strip_prefix("UDP="))
+        .filter_map(|address| socket_mod::parse_address(socket_mod::split_device(address).0).ok())
+        .collect();
+
+    let mut servers: HashMap<Option<String>, ServerFuture<Handler>> = HashMap::new();
+    let mut bound_policies: HashSet<Option<String>> = HashSet::new();
+    let mut failures: Vec<ErrorKind> = vec![];
+    for bind in binds.into_iter() {
+        let (address, policy) = socket_mod::split_policy(&bind);
+        let policy = policy.map(str::to_string);
+        let server = servers.entry(policy.clone()).or_insert_with(|| ServerFuture::new(new_handler(policy.clone())));
+
+        if let Err(error) = setup_bind(config, server, address, &udp_addresses).await {
+            let failure = ErrorKind::BindFailed {bind: bind.clone(), source: Box::new(error)};
+            warn!("{}: {}", config.daemon_id, failure);
+            failures.push(failure);
+            continue
+        }
+        bound_policies.insert(policy);
     }
-    if successful_binds_count == bind_count {
+    let successful_binds_count = bind_count - failures.len() as u32;
+    if successful_binds_count == 0 {
+        return Err(WrappedErrors::DNSlrError(ErrorKind::NoBindSet(failures)))
+    } else if successful_binds_count == bind_count {
         info!("{}: all {} binds were set", config.daemon_id, successful_binds_count)
-    } else if successful_binds_count < bind_count {
+    } else {
         warn!("{}: {} out of {} total binds were set", config.daemon_id, successful_binds_count, bind_count)
-    } else if successful_binds_count == 0 {
-        error!("{}: No bind was set", config.daemon_id);
-        return Err(WrappedErrors::DNSlrError(ErrorKind::SetupBindingError))
     }
 
-    return Ok(())
+    // A server without any bind would never finish, so only those with binds are kept
+    servers.retain(|policy, _| bound_policies.contains(policy));
+    return Ok(servers.into_values().collect())
+}
+
+async fn reload_config (
+    arc_config: &ArcSwap<Config>,
+    arc_resolver: &ArcSwap<ForwarderPool>,
+    match_cache: &MatchCache,
+    backend: &dyn Backend
+)
+-> bool {
+    if let Err(error) = backend.refresh().await {
+        error!("Could not read the backend again, keeping the previous config: {}", error);
+        return false
+    }
+
+    // The new config is fully built and validated aside, the running one is only replaced once it is sound
+    let new_config = match redis_mod::build_config(backend).await {
+        Ok(new_config) => new_config,
+        Err(error) => {
+            error!("Could not rebuild the config, keeping the previous one: {}", error);
+            return false
+        }
+    };
+
+    // The sockets are only bound at startup
+    let old_config = arc_config.load();
+    if new_config.binds != old_config.binds || new_config.reuseport != old_config.reuseport
+        || new_config.enable_udp != old_config.enable_udp || new_config.enable_tcp != old_config.enable_tcp {
+        warn!("{}: binds, reuseport, enable_udp or enable_tcp changed, restart the server to apply them", new_config.daemon_id)
+    }
+
+    // Rebuilding the resolver drops its cache, so it is only done when its settings changed
+    if resolver_mod::resolver_changed(&arc_config.load(), &new_config) {
+        let resolver = match resolver_mod::build_resolver(&new_config) {
+            Ok(resolver) => resolver,
+            Err(error) => {
+                error!("Could not rebuild the resolver, keeping the previous config: {}", error);
+                return false
+            }
+        };
+        arc_resolver.store(Arc::new(resolver));
+        info!("Resolver was rebuilt")
+    }
+    log_matchclasses_diff(&arc_config.load(), &new_config);
+    match_cache.flush(new_config.match_cache.map(|(size, _)| size));
+    arc_config.store(Arc::new(new_config));
+    info!("Config was rebuilt");
+
+    return true
+}
+
+async fn reload_matchclasses (
+    arc_config: &ArcSwap<Config>,
+    match_cache: &MatchCache,
+    backend: &dyn Backend
+) {
+    if let Err(error) = backend.refresh().await {
+        error!("Could not read the backend again, keeping the previous matchclasses: {}", error);
+        return
+    }
+
+    let mut new_config = (**arc_config.load()).clone();
+    if let Err(error) = redis_mod::build_filtering(backend, &mut new_config).await {
+        error!("Could not reload the matchclasses: {}", error);
+        return
+    };
+    log_matchclasses_diff(&arc_config.load(), &new_config);
+    match_cache.flush(new_config.match_cache.map(|(size, _)| size));
+    arc_config.store(Arc::new(new_config));
+    info!("Matchclasses were reloaded")
+}
+
+// The matchclasses of the default filtering and of each policy, the latter suffixed with "#policy" like their binds
+fn matchclass_names (
+    config: &Config
+)
+-> HashSet<String> {
+    let default = config.matchclasses.iter().flatten()
+        .map(|matchclass| matchclass.name.clone());
+    let policies = config.policies.iter()
+        .flat_map(|(policy, matchclasses)| matchclasses.iter().map(move |matchclass| format!("{}#{}", matchclass.name, policy)));
+    return default.chain(policies).collect()
+}
+
+// Compares the running matchclasses with the reloaded ones before they are swapped in
+fn log_matchclasses_diff (
+    old_config: &Config,
+    new_config: &Config
+) {
+    let old_names = matchclass_names(old_config);
+    let new_names = matchclass_names(new_config);
+
+    let mut added: Vec<&str> = new_names.difference(&old_names).map(String::as_str).collect();
+    let mut removed: Vec<&str> = old_names.difference(&new_names).map(String::as_str).collect();
+    added.sort();
+    removed.sort();
+
+    info!("{}: Matchclasses: {} added, {} removed, {} kept", new_config.daemon_id, added.len(), removed.len(), new_names.len() - added.len());
+    if !added.is_empty() {
+        debug!("{}: Added matchclasses: {}", new_config.daemon_id, added.join(", "))
+    }
+    if !removed.is_empty() {
+        debug!("{}: Removed matchclasses: {}", new_config.daemon_id, removed.join(", "))
+    }
+}
+
+async fn handle_pubsub (
+    mut pubsub: redis::aio::PubSub,
+    arc_config: Arc<ArcSwap<Config>>,
+    arc_resolver: Arc<ArcSwap<ForwarderPool>>,
+    match_cache: Arc<MatchCache>,
+    backend: Arc<dyn Backend>
+) {
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let Ok(payload) = message.get_payload::<String>() else {
+            warn!("Received a reload message that is not a string");
+            continue
+        };
+
+        match payload.as_str() {
+            "config" => {
+                info!("Received a config reload message");
+                reload_config(&arc_config, &arc_resolver, &match_cache, backend.as_ref()).await;
+            },
+            "matchclasses" => {
+                info!("Received a matchclasses reload message");
+                reload_matchclasses(&arc_config, &match_cache, backend.as_ref()).await
+            },
+            _ => warn!("Received an unknown reload message: {}", payload)
+        }
+    }
+    warn!("The reload subscription was closed");
+}
+
+async fn handle_blocklists (
+    arc_config: Arc<ArcSwap<Config>>,
+    match_cache: Arc<MatchCache>,
+    mut redis_manager: redis::aio::ConnectionManager,
+    backend: Arc<dyn Backend>,
+    refresh: std::time::Duration
+) {
+    let mut fetcher = BlocklistFetcher::new();
+    let mut interval = tokio::time::interval(refresh);
+    loop {
+        interval.tick().await;
+
+        let config = arc_config.load_full();
+        if fetcher.refresh(&mut redis_manager, &config).await {
+            reload_matchclasses(&arc_config, &match_cache, backend.as_ref()).await
+        }
+    }
+}
+
+// Waits for the queries in flight, those left when drain_timeout is over are cancelled with the runtime
+async fn drain (
+    inflight: &Inflight,
+    drain_timeout: std::time::Duration,
+    daemon_id: &str
+) {
+    match inflight.wait_idle(tokio::time::Instant::now() + drain_timeout).await {
+        0 => info!("{}: Every query in flight was answered", daemon_id),
+        remaining => warn!("{}: {} queries were still in flight after {}s, they are cancelled", daemon_id, remaining, drain_timeout.as_secs())
+    }
+}
+
+// One lookup through the forwarders the name would be forwarded to, the number of records it got
+async fn selftest (
+    resolver: &ForwarderPool,
+    name: &Name
+)
+-> DnsLrResult<usize> {
+    let zone_resolver = resolver.pick_zone(name).map(|(zone_resolver, _)| zone_resolver);
+    return match zone_resolver.unwrap_or_else(|| resolver.pick()).lookup(name.clone(), RecordType::A).await {
+        Ok(lookup) => Ok(lookup.records().len()),
+        Err(error) => Err(WrappedErrors::DNSlrError(ErrorKind::SelftestFailed {name: name.to_string(), source: error}))
+    }
+}
+
+// Forwarders failing too many probes in a row are left out of the resolver until they answer again
+async fn handle_forwarder_probes (
+    arc_config: Arc<ArcSwap<Config>>,
+    arc_resolver: Arc<ArcSwap<ForwarderPool>>,
+    probe_interval: std::time::Duration,
+    failure_threshold: u32
+) {
+    let mut failures: HashMap<Forwarder, u32> = HashMap::new();
+    let mut interval = tokio::time::interval(probe_interval);
+    loop {
+        interval.tick().await;
+
+        let config = arc_config.load_full();
+        let results = future::join_all(config.forwarders.iter().map(|forwarder| resolver_mod::probe(forwarder, config.forwarder_source_ip))).await;
+        for (forwarder, healthy) in config.forwarders.iter().zip(results) {
+            let count = failures.entry(forwarder.clone()).or_insert(0);
+            match healthy {
+                true => {
+                    if *count >= failure_threshold {
+                        info!("{}: Forwarder: {} answers again, adding it back", config.daemon_id, forwarder)
+                    }
+                    *count = 0
+                },
+                false => {
+                    *count += 1;
+                    if *count == failure_threshold {
+                        warn!("{}: Forwarder: {} failed {} probes in a row, leaving it out", config.daemon_id, forwarder, failure_threshold)
+                    }
+                }
+            }
+        }
+        failures.retain(|forwarder, _| config.forwarders.contains(forwarder));
+
+        let mut healthy_forwarders: Vec<Forwarder> = config.forwarders.iter()
+            .filter(|forwarder| failures.get(forwarder).is_none_or(|count| *count < failure_threshold))
+            .cloned()
+            .collect();
+        // With every forwarder down, leaving them all in beats having none to try
+        if healthy_forwarders.is_empty() {
+            healthy_forwarders = config.forwarders.clone()
+        }
+
+        // A config reload rebuilds the resolver with every forwarder, this puts the exclusions back
+        if arc_resolver.load().forwarders() != healthy_forwarders.as_slice() {
+            let mut probed_config = (*config).clone();
+            probed_config.forwarders = healthy_forwarders;
+            match resolver_mod::build_resolver(&probed_config) {
+                Ok(resolver) => {
+                    arc_resolver.store(Arc::new(resolver));
+                    info!("{}: Resolver was rebuilt with {} out of {} forwarders", config.daemon_id, probed_config.forwarders.len(), config.forwarders.len())
+                },
+                Err(error) => error!("{}: Could not rebuild the resolver after the probes: {}", config.daemon_id, error)
+            }
+        }
+    }
+}
+
+// A failed flush is retried on the next tick, the counters keep going in memory meanwhile
+async fn handle_stats_persist (
+    arc_config: Arc<ArcSwap<Config>>,
+    mut redis_manager: redis::aio::ConnectionManager,
+    stats: Arc<Stats>,
+    persist_interval: std::time::Duration
+) {
+    let mut interval = tokio::time::interval(persist_interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+
+        let daemon_id = arc_config.load().daemon_id.clone();
+        if let Err(error) = redis_mod::persist_stats(&mut redis_manager, &stats, &daemon_id).await {
+            warn!("{}: Could not flush the counters to Redis: {}", daemon_id, error)
+        }
+    }
+}
+
+// Tells systemd about the state of the service, nothing is sent when it did not start it with NOTIFY_SOCKET set
+fn notify_systemd (
+    states: &[NotifyState]
+) {
+    if let Err(error) = sd_notify::notify(false, states) {
+        warn!("Could not notify systemd: {}", error)
+    }
 }
 
 async fn handle_signals (
     mut signals: Signals,
     arc_config: Arc<ArcSwap<Config>>,
-    mut redis_manager: redis::aio::ConnectionManager
+    arc_resolver: Arc<ArcSwap<ForwarderPool>>,
+    match_cache: Arc<MatchCache>,
+    backend: Arc<dyn Backend>,
+    stats: Arc<Stats>,
+    shutdown: Arc<Notify>
 ) {
     while let Some(signal) = signals.next().await {
         match signal {
             SIGHUP => {
                 info!("Captured SIGHUP");
 
-                let Ok(new_config) = redis_mod::build_config(&mut redis_manager).await else {
-                    error!("Could not rebuild the config");
-                    continue
-                };
-                let new_config =  Arc::new(new_config);
-                arc_config.store(new_config);
-                info!("Config was rebuilt")
+                // A Type=notify-reload unit waits for READY=1 again, it needs the monotonic time the reload started at
+                let mut reloading = vec![NotifyState::Reloading];
+                reloading.extend(NotifyState::monotonic_usec_now().ok());
+                notify_systemd(&reloading);
+
+                // Persisted counters are long-term totals, a reload does not start them over
+                if reload_config(&arc_config, &arc_resolver, &match_cache, backend.as_ref()).await && arc_config.load().stats_persist.is_none() {
+                    stats.reset()
+                }
+                notify_systemd(&[NotifyState::Ready]);
             },
             SIGUSR1 => {
                 info!("Captured SIGUSR1");
+
+                reload_matchclasses(&arc_config, &match_cache, backend.as_ref()).await;
             },
             SIGUSR2 => {
                 info!("Captured SIGUSR2");
 
+                info!("{}: Queries: {}, filtered: {}, forwarded: {}, NXDOMAIN: {}, forward errors: {}, Redis errors: {}, rate limited: {}, static answers: {}, unauthorized: {}, overloaded: {}, RRL limited: {}, match cache hits: {}, match cache misses: {}, coalesced: {}, malformed dropped: {}, policy service errors: {}",
+                    arc_config.load().daemon_id,
+                    stats.queries.load(Ordering::Relaxed),
+                    stats.filtered.load(Ordering::Relaxed),
+                    stats.forwarded.load(Ordering::Relaxed),
+                    stats.nxdomains.load(Ordering::Relaxed),
+                    stats.forward_errors.load(Ordering::Relaxed),
+                    stats.redis_errors.load(Ordering::Relaxed),
+                    stats.rate_limited.load(Ordering::Relaxed),
+                    stats.static_answers.load(Ordering::Relaxed),
+                    stats.unauthorized.load(Ordering::Relaxed),
+                    stats.overloaded.load(Ordering::Relaxed),
+                    stats.rrl_limited.load(Ordering::Relaxed),
+                    stats.match_cache_hits.load(Ordering::Relaxed),
+                    stats.match_cache_misses.load(Ordering::Relaxed),
+                    stats.coalesced.load(Ordering::Relaxed),
+                    stats.malformed_dropped.load(Ordering::Relaxed),
+                    stats.policy_service_errors.load(Ordering::Relaxed)
+                );
+                let response_codes: Vec<String> = stats.response_codes.labeled().iter()
+                    .map(|(rcode, count)| format!("{}: {}", rcode, count))
+                    .collect();
+                info!("{}: Responses: {}", arc_config.load().daemon_id, response_codes.join(", "));
+                let top_blocked: Vec<String> = stats.top_blocked.top(top_blocked_mod::TOP_BLOCKED).iter()
+                    .map(|(domain, count)| format!("{} ({})", domain, count))
+                    .collect();
+                if !top_blocked.is_empty() {
+                    info!("{}: Most filtered domains: {}", arc_config.load().daemon_id, top_blocked.join(", "))
+                }
+
+                let config = arc_config.load();
+                if let Some(stats_file) = &config.stats_file {
+                    if let Err(error) = metrics_mod::write_stats_file(&stats, stats_file, &config.daemon_id) {
+                        warn!("{}: Could not write the counters to {}: {}", config.daemon_id, stats_file, error)
+                    }
+                }
+            },
+            SIGTERM | SIGINT => {
+                info!("Captured SIGTERM or SIGINT");
+
+                notify_systemd(&[NotifyState::Stopping]);
+                shutdown.notify_one();
+                break
             },
             _ => unreachable!()
         }
     }
 }
 
-#[tokio::main]
-async fn main()
+fn main()
+-> ExitCode {
+    let mut arguments: Vec<String> = env::args().skip(1).collect();
+
+    // Answered before the runtime, Redis or the config file are involved
+    if arguments.iter().any(|argument| argument == "--version") {
+        println!("{} {} (commit {}, built {})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), env!("DNSLIAR_GIT_COMMIT"), env!("DNSLIAR_BUILD_DATE"));
+        return ExitCode::SUCCESS
+    }
+
+    // The config path comes from the first argument, then DNSLIAR_CONFILE, then the working directory
+    let confile_path = match arguments.first() {
+        Some(first) if !first.starts_with("--") => arguments.remove(0),
+        _ => env::var("DNSLIAR_CONFILE").unwrap_or_else(|_| DEFAULT_CONFILE.to_string())
+    };
+
+    // The runtime and the logging are set up from the config file, so its errors can only go to stderr
+    let confile = match read_confile(&confile_path) {
+        Ok(ok) => ok,
+        Err(error) => {
+            eprintln!("{}", error);
+            return ExitCode::FAILURE
+        }
+    };
+    let _ = CONFILE.0.set(confile);
+
+    let runtime = match build_runtime() {
+        Ok(runtime) => runtime,
+        Err(error) => {
+            eprintln!("Could not start the runtime: {}", error);
+            return ExitCode::FAILURE
+        }
+    };
+    return runtime.block_on(start(confile_path, arguments))
+}
+
+// The multi-thread runtime with a worker per core unless the config file says otherwise
+fn build_runtime ()
+-> io::Result<tokio::runtime::Runtime> {
+    let mut builder = match CONFILE.runtime_flavor.as_deref() {
+        Some("current_thread") => tokio::runtime::Builder::new_current_thread(),
+        _ => tokio::runtime::Builder::new_multi_thread()
+    };
+    if let Some(worker_threads) = CONFILE.worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+    return builder.enable_all().build()
+}
+
+async fn start (
+    confile_path: String,
+    arguments: Vec<String>
+)
+-> ExitCode {
+    init_logging();
+    daemon_span().in_scope(|| {
+        info!("{}: Config file: {}", CONFILE.daemon_id, confile_path);
+        match (CONFILE.runtime_flavor.as_deref(), CONFILE.worker_threads) {
+            (Some("current_thread"), _) => info!("{}: Runtime: current thread", CONFILE.daemon_id),
+            (_, Some(worker_threads)) => info!("{}: Runtime: multi thread with {} workers", CONFILE.daemon_id, worker_threads),
+            (_, None) => ()
+        }
+    });
+
+    let result = run(arguments).instrument(daemon_span()).await;
+    telemetry_mod::shutdown().await;
+
+    return match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            daemon_span().in_scope(|| error!("{}: {}", CONFILE.daemon_id, error));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_arguments (
+    arguments: &[String]
+)
+-> DnsLrResult<Command> {
+    let mut check_config = false;
+    let mut path = None;
+    let mut matchclass = None;
+    let mut listed_matchclass = None;
+
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--check-config" => check_config = true,
+            "--import-hosts" => path = arguments.next().cloned(),
+            "--matchclass" => matchclass = arguments.next().cloned(),
+            "--list-matchclass" => listed_matchclass = arguments.next().cloned(),
+            _ => return Err(WrappedErrors::DNSlrError(ErrorKind::InvalidArguments(format!("unknown argument: {}", argument))))
+        }
+    }
+
+    return match (check_config, path, matchclass, listed_matchclass) {
+        (false, None, None, None) => Ok(Command::Serve),
+        (true, None, None, None) => Ok(Command::CheckConfig),
+        (false, Some(path), Some(matchclass), None) => Ok(Command::ImportHosts(path, matchclass)),
+        (false, None, None, Some(listed_matchclass)) => Ok(Command::ListMatchclass(listed_matchclass)),
+        _ => Err(WrappedErrors::DNSlrError(ErrorKind::InvalidArguments(
+            "usage: dnsliar-rs [config path] [--check-config | --import-hosts <path> --matchclass <name> | --list-matchclass <name>] | --version".to_string()
+        )))
+    }
+}
+
+// Prints the domains of a matchclass one per line on stdout, page by page so a huge matchclass is never held whole
+async fn list_matchclass (
+    matchclass: &str
+)
 -> DnsLrResult<()> {
-    tracing_subscriber::fmt::init();
+    if CONFILE.is_file_backend() {
+        return Err(WrappedErrors::DNSlrError(ErrorKind::RedisBackendRequired("--list-matchclass".to_string())))
+    }
+    let mut redis_manager = redis_mod::build_manager().await?;
 
-    let signals = Signals::new(&[SIGHUP, SIGUSR1, SIGUSR2])?;
+    let mut cursor = 0;
+    let mut count: u64 = 0;
+    loop {
+        let (next_cursor, domains) = redis_mod::scan_matchclass(&mut redis_manager, matchclass, cursor, redis_mod::SCAN_PAGE_SIZE).await?;
+        for domain in domains {
+            println!("{}", domain);
+            count += 1
+        }
+        if next_cursor == 0 {
+            break
+        }
+        cursor = next_cursor
+    }
+
+    info!("{}: Matchclass: {} lists {} domains", CONFILE.daemon_id, matchclass, count);
+    return Ok(())
+}
+
+// The Redis pool is only opened with the Redis backend, it then serves as the backend too
+async fn build_backend ()
+-> DnsLrResult<(Arc<dyn Backend>, Option<RedisPool>)> {
+    if let (true, Some(backend_file)) = (CONFILE.is_file_backend(), &CONFILE.backend_file) {
+        return Ok((Arc::new(FileBackend::load(backend_file)?), None))
+    }
+    let redis_pool = redis_mod::build_pool().await?;
+    return Ok((Arc::new(redis_pool.clone()), Some(redis_pool)))
+}
+
+// Builds the config like a startup would, without binding anything
+async fn check_config ()
+-> DnsLrResult<()> {
+    let (backend, _) = build_backend().await?;
+    let config = redis_mod::build_config(backend.as_ref()).await?;
+    resolver_mod::build_resolver(&config)?;
+
+    info!("{}: The config is valid", config.daemon_id);
+    return Ok(())
+}
+
+async fn run (
+    arguments: Vec<String>
+)
+-> DnsLrResult<()> {
+    match parse_arguments(&arguments)? {
+        Command::Serve => (),
+        Command::CheckConfig => return check_config().await,
+        Command::ImportHosts(..) if CONFILE.is_file_backend() => {
+            return Err(WrappedErrors::DNSlrError(ErrorKind::RedisBackendRequired("--import-hosts".to_string())))
+        },
+        Command::ImportHosts(path, matchclass) => {
+            let mut redis_manager = redis_mod::build_manager().await?;
+            return import_mod::import_hosts(&mut redis_manager, &path, &matchclass, &CONFILE.daemon_id).await
+        },
+        Command::ListMatchclass(matchclass) => return list_matchclass(&matchclass).await
+    }
+
+    let signals = Signals::new([SIGHUP, SIGUSR1, SIGUSR2, SIGTERM, SIGINT])?;
     let signals_handler = signals.handle();
 
-    let mut redis_manager = redis_mod::build_manager().await?;
-    let config = redis_mod::build_config(&mut redis_manager).await?;
-    let resolver = resolver_mod::build_resolver(&config);
+    let (backend, redis_pool) = build_backend().await?;
+    let config = redis_mod::build_config(backend.as_ref()).await?;
+    let resolver = resolver_mod::build_resolver(&config)?;
 
     info!("{}: Initializing server...", config.daemon_id);
     let arc_config = Arc::new(ArcSwap::from_pointee(config.clone()));
+    let arc_resolver = Arc::new(ArcSwap::from_pointee(resolver));
+
+    let stats = Arc::new(Stats::default());
+    if let (true, Some(redis_pool)) = (config.stats_persist.is_some(), &redis_pool) {
+        match redis_mod::restore_stats(&mut redis_pool.get(), &stats, &config.daemon_id).await {
+            Ok(()) => info!("{}: Counters were restored from stats_{}", config.daemon_id, config.daemon_id),
+            Err(error) => warn!("{}: Could not restore the counters, starting from 0: {}", config.daemon_id, error)
+        }
+    }
 
-    let handler = Handler {
-        redis_manager: redis_manager.clone(), resolver, config: Arc::clone(&arc_config)
+    let rate_limiter = Arc::new(RateLimiter::new());
+    let inflight = Arc::new(Inflight::default());
+    let draining = Arc::new(AtomicBool::new(false));
+    let response_rate_limiter = Arc::new(ResponseRateLimiter::new());
+    let match_cache = Arc::new(MatchCache::new(config.match_cache.map_or(1, |(size, _)| size)));
+    let coalescer = Arc::new(Coalescer::default());
+    let policy_service = Arc::new(PolicyServiceClient::new());
+    let new_handler = |policy: Option<String>| Handler {
+        backend: Arc::clone(&backend),
+        redis_pool: redis_pool.clone(),
+        resolver: Arc::clone(&arc_resolver),
+        config: Arc::clone(&arc_config),
+        stats: Arc::clone(&stats),
+        rate_limiter: Arc::clone(&rate_limiter),
+        policy,
+        blackhole_rotation: AtomicUsize::new(0),
+        inflight: Arc::clone(&inflight),
+        response_rate_limiter: Arc::clone(&response_rate_limiter),
+        match_cache: Arc::clone(&match_cache),
+        coalescer: Arc::clone(&coalescer),
+        policy_service: Arc::clone(&policy_service),
+        draining: Arc::clone(&draining)
     };
-    
-    let signals_task = tokio::task::spawn(handle_signals(signals, Arc::clone(&arc_config), redis_manager));
 
-    let mut server = ServerFuture::new(handler);
+    let mut pubsub_task = None;
+    if let (true, Some(_)) = (config.pubsub_reload, &redis_pool) {
+        let channel = redis_mod::key(&format!("reload_{}", config.daemon_id));
+        let pubsub = redis_mod::build_pubsub(&channel).await?;
+        pubsub_task = Some(tokio::task::spawn(handle_pubsub(pubsub, Arc::clone(&arc_config), Arc::clone(&arc_resolver), Arc::clone(&match_cache), Arc::clone(&backend)).in_current_span()));
+        info!("{}: Listening for reload messages on {}", config.daemon_id, channel)
+    }
+
+    let mut blocklists_task = None;
+    if let (false, Some(redis_pool)) = (config.blocklists.is_empty(), &redis_pool) {
+        blocklists_task = Some(tokio::task::spawn(handle_blocklists(Arc::clone(&arc_config), Arc::clone(&match_cache), redis_pool.get(), Arc::clone(&backend), config.blocklist_refresh).in_current_span()))
+    }
+
+    let mut probes_task = None;
+    if let Some((probe_interval, failure_threshold)) = config.forwarder_probe {
+        probes_task = Some(tokio::task::spawn(handle_forwarder_probes(Arc::clone(&arc_config), Arc::clone(&arc_resolver), probe_interval, failure_threshold).in_current_span()))
+    }
+
+    let mut stats_persist_task = None;
+    if let (Some(persist_interval), Some(redis_pool)) = (config.stats_persist, &redis_pool) {
+        stats_persist_task = Some(tokio::task::spawn(handle_stats_persist(Arc::clone(&arc_config), redis_pool.get(), Arc::clone(&stats), persist_interval).in_current_span()))
+    }
 
-    setup_binds(&mut server, &config).await?;
+    let shutdown = Arc::new(Notify::new());
+    let signals_task = tokio::task::spawn(handle_signals(signals, Arc::clone(&arc_config), Arc::clone(&arc_resolver), Arc::clone(&match_cache), Arc::clone(&backend), Arc::clone(&stats), Arc::clone(&shutdown)).in_current_span());
+
+    let mut metrics_task = None;
+    if let Some(metrics_bind) = config.metrics_bind {
+        match TcpListener::bind(metrics_bind).await {
+            Ok(listener) => {
+                metrics_task = Some(tokio::task::spawn(metrics_mod::serve(listener, Arc::clone(&stats), config.daemon_id.clone()).in_current_span()));
+                info!("{}: Metrics are served on {}", config.daemon_id, metrics_bind)
+            },
+            Err(_) => warn!("{}: Failed to bind the metrics endpoint: {}", config.daemon_id, metrics_bind)
+        }
+    }
+
+    let mut health_task = None;
+    if let Some(health_bind) = config.health_bind {
+        match TcpListener::bind(health_bind).await {
+            Ok(listener) => {
+                health_task = Some(tokio::task::spawn(health_mod::serve(listener, redis_pool.as_ref().map(RedisPool::get), Arc::clone(&arc_resolver), config.daemon_id.clone()).in_current_span()));
+                info!("{}: Health checks are served on {}", config.daemon_id, health_bind)
+            },
+            Err(_) => warn!("{}: Failed to bind the health endpoint: {}", config.daemon_id, health_bind)
+        }
+    }
+
+    let servers = setup_binds(&config, new_handler).await?;
+    let servers_done = future::select_all(servers.into_iter().map(|server| Box::pin(server.block_until_done())));
+
+    if let Some((selftest_name, selftest_strict)) = &config.selftest {
+        match selftest(&arc_resolver.load(), selftest_name).await {
+            Ok(answers) => info!("{}: Self-test: {} resolved to {} records", config.daemon_id, selftest_name, answers),
+            Err(error) if *selftest_strict => return Err(error),
+            Err(error) => warn!("{}: {}", config.daemon_id, error)
+        }
+    }
 
     info!("{}: Server started", config.daemon_id);
-    server.block_until_done().await?;
+    // The sockets are bound by now, so units ordered after this one can query it right away
+    notify_systemd(&[NotifyState::Ready]);
+    tokio::select! {
+        (result, _, _) = servers_done => result?,
+        _ = shutdown.notified() => info!("{}: Shutting down the server...", config.daemon_id)
+    }
+    draining.store(true, Ordering::Relaxed);
+    drain(&inflight, arc_config.load().drain_timeout, &config.daemon_id).await;
 
+    if let Some(metrics_task) = metrics_task {
+        metrics_task.abort()
+    }
+    if let Some(health_task) = health_task {
+        health_task.abort()
+    }
+    if let Some(pubsub_task) = pubsub_task {
+        pubsub_task.abort()
+    }
+    if let Some(blocklists_task) = blocklists_task {
+        blocklists_task.abort()
+    }
+    if let Some(probes_task) = probes_task {
+        probes_task.abort()
+    }
+    if let (Some(stats_persist_task), Some(redis_pool)) = (stats_persist_task, &redis_pool) {
+        stats_persist_task.abort();
+        // The last flush keeps what was counted since the previous tick
+        if let Err(error) = redis_mod::persist_stats(&mut redis_pool.get(), &stats, &config.daemon_id).await {
+            warn!("{}: Could not flush the counters to Redis: {}", config.daemon_id, error)
+        }
+    }
     signals_handler.close();
     signals_task.await?;
 
+    info!("{}: Server stopped", config.daemon_id);
+    io::stdout().flush()?;
+
     return Ok(())
 }
@@ -0,0 +1,73 @@
+use crate::enums_structs::Matchclass;
+
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant}
+};
+use trust_dns_proto::rr::RecordType;
+
+// The policy of the bind, the normalized query name and the qtype
+pub type Key = (Option<String>, String, RecordType);
+
+struct Entry {
+    // The deciding matchclass, None when the name is unmatched
+    matchclass: Option<Matchclass>,
+    expires: Instant
+}
+
+// Recent match decisions, so that hot names do not cost a Redis round trip on every query
+pub struct MatchCache {
+    entries: Mutex<LruCache<Key, Entry>>
+}
+impl MatchCache {
+    pub fn new (
+        capacity: usize
+    )
+    -> MatchCache {
+        return MatchCache {
+            entries: Mutex::new(LruCache::new(NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN)))
+        }
+    }
+
+    // None on a miss, Some(None) when the name was found unmatched
+    pub fn get (
+        &self,
+        key: &Key
+    )
+    -> Option<Option<Matchclass>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        if entry.expires <= Instant::now() {
+            entries.pop(key);
+            return None
+        }
+        return Some(entry.matchclass.clone())
+    }
+
+    pub fn insert (
+        &self,
+        key: Key,
+        matchclass: Option<Matchclass>,
+        ttl: Duration
+    ) {
+        let entry = Entry {
+            matchclass,
+            expires: Instant::now() + ttl
+        };
+        self.entries.lock().unwrap().put(key, entry);
+    }
+
+    // Every decision may be stale once the matchclasses change, the size follows the new config
+    pub fn flush (
+        &self,
+        capacity: Option<usize>
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clear();
+        if let Some(capacity) = capacity.and_then(NonZeroUsize::new) {
+            entries.resize(capacity)
+        }
+    }
+}
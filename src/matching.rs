@@ -1,81 +1,592 @@
 use crate::Config;
-use crate::enums_structs::DnsLrResult;
-use crate::resolver_mod;
+use crate::enums_structs::{DnsLrResult, FilterMode, Matchclass, MatchMode, QtypeFilter, SvcbFilterMode};
 use crate::redis_mod;
+use crate::backend_mod::Backend;
+use crate::policy_service_mod::Decision;
 
-use trust_dns_client::op::Header;
-use trust_dns_client::rr::{RData, RecordType, Record};
+use trust_dns_client::op::{Header, ResponseCode};
+use trust_dns_client::rr::{DNSClass, RData, RecordType, Record, Name, rdata::{HINFO, SOA, TXT}};
 use trust_dns_server::server::Request;
-use trust_dns_resolver::{
-    AsyncResolver,
-    name_server::{GenericConnection, GenericConnectionProvider, TokioRuntime}
-};
 
 use tracing::info;
-use smallvec::{SmallVec, ToSmallVec, smallvec};
-use arc_swap::Guard;
-use std::sync::Arc;
+use std::net::IpAddr;
+use rand::Rng;
+use smallvec::{SmallVec, smallvec};
+
+const MAX_ANCESTOR_LOOKUPS: usize = 4;
+// The reserved matchclass of the names always answered with NXDOMAIN, its keys are "nxdomain:{domain}"
+pub const NXDOMAIN_MATCHCLASS: &str = "nxdomain";
+// The name the decisions of the policy service are logged and counted under
+pub const POLICY_SERVICE_MATCHCLASS: &str = "policy_service";
+// The reserved zone of the debug queries, never forwarded when they are enabled
+const DEBUG_ZONE: &str = "._dnsliar.";
+const SOA_REFRESH: i32 = 3600;
+const SOA_RETRY: i32 = 600;
+const SOA_EXPIRE: i32 = 86400;
+const CHAOS_VERSION_NAMES: [&str; 2] = ["version.bind.", "version.server."];
+const CHAOS_ID_NAMES: [&str; 2] = ["hostname.bind.", "id.server."];
+const ANY_HINFO_TTL: u32 = 3600;
+const ANY_HINFO_CPU: &str = "RFC8482";
+
+// The canonical form of the entries of a matchclass, a wildcard entry keeps its leading dot: ".example.com."
+pub fn normalize_entry (
+    entry: &str
+)
+-> Option<String> {
+    return match entry.strip_prefix('.') {
+        Some(name) => normalize_name(name).map(|name| format!(".{}", name)),
+        None => normalize_name(entry)
+    }
+}
+
+// The canonical form of the names in the "{matchclass}:{domain}" keys: lowercase A-labels with a trailing dot
+// None when the name is empty or not a valid domain name
+pub fn normalize_name (
+    name: &str
+)
+-> Option<String> {
+    if name.is_empty() || name == "." {
+        return None
+    }
+    let Ok(name) = Name::from_utf8(name) else {
+        return None
+    };
+
+    let mut name = name.to_lowercase().to_ascii();
+    if !name.ends_with('.') {
+        name.push('.')
+    }
+    return Some(name)
+}
+
+// The matchclass standing for a decision of the policy service, an allow one when the query is to be forwarded
+pub fn policy_service_matchclass (
+    decision: Decision
+)
+-> Matchclass {
+    let mut matchclass = Matchclass {
+        name: POLICY_SERVICE_MATCHCLASS.to_string(),
+        is_allow: false,
+        is_nxdomain: false,
+        is_redirect: false,
+        blackhole_ipv4s: vec![],
+        blackhole_ipv6s: vec![],
+        qtypes: QtypeFilter::All,
+        match_mode: MatchMode::Exact
+    };
+    match decision {
+        Decision::Allow => matchclass.is_allow = true,
+        Decision::Block => (),
+        Decision::Redirect(addresses) => {
+            matchclass.is_redirect = true;
+            for address in addresses {
+                match address {
+                    IpAddr::V4(ipv4) => matchclass.blackhole_ipv4s.push((ipv4, 1)),
+                    IpAddr::V6(ipv6) => matchclass.blackhole_ipv6s.push((ipv6, 1))
+                }
+            }
+        }
+    }
+    return matchclass
+}
+
+pub fn nxdomain_matchclass (
+    match_mode: MatchMode
+)
+-> Matchclass {
+    return Matchclass {
+        name: NXDOMAIN_MATCHCLASS.to_string(),
+        is_allow: false,
+        is_nxdomain: true,
+        is_redirect: false,
+        blackhole_ipv4s: vec![],
+        blackhole_ipv6s: vec![],
+        // A name that does not exist has no record of any type
+        qtypes: QtypeFilter::All,
+        match_mode
+    }
+}
+
+// The svcb_filter_mode of a filtered SVCB or HTTPS query, None for the other qtypes or when filter_mode applies
+fn svcb_filter_mode (
+    request: &Request,
+    config: &Config
+)
+-> Option<SvcbFilterMode> {
+    return match request.query().query_type() {
+        RecordType::SVCB | RecordType::HTTPS => config.svcb_filter_mode,
+        _ => None
+    }
+}
+
+// Whether a filtered query is answered with NXDOMAIN, which also gets it a negative SOA
+pub fn is_nxdomain_lie (
+    request: &Request,
+    config: &Config,
+    matchclass: &Matchclass
+)
+-> bool {
+    if matchclass.is_nxdomain || matchclass.is_redirect {
+        return matchclass.is_nxdomain
+    }
+    return match svcb_filter_mode(request, config) {
+        Some(svcb_filter_mode) => svcb_filter_mode == SvcbFilterMode::Nxdomain,
+        None => config.filter_mode == FilterMode::Nxdomain
+    }
+}
+
+// The query name in the form of the keys, as is when it does not normalize
+pub fn query_name (
+    request: &Request
+)
+-> String {
+    let raw_name = request.query().name().to_string();
+    return normalize_name(&raw_name).unwrap_or(raw_name)
+}
 
 pub async fn filter (
     request: &Request,
-    header: Header,
-    config: Guard<Arc<Config>>,
-    mut redis_manager: redis::aio::ConnectionManager,
-    resolver: AsyncResolver<GenericConnection, GenericConnectionProvider<TokioRuntime>>
-)
--> DnsLrResult<(Vec<Record>, Header)> {
-    let domain_name = request.query().name().to_string();
-    let names = domain_name.split('.');
-
-    let name_count = names.clone().count();
-    let filter_5: [u8; 5] = [3, 4, 2, 5, 1];
-    let mut order: SmallVec<[u8; 5]> = smallvec![];
-    match name_count {
-        1 => order = smallvec![1],
-        2 => order = smallvec![2, 1],
-        3 => order = smallvec![3, 2, 1],
-        4 => order = smallvec![3, 4, 2, 1],
-        5 => order = filter_5.to_smallvec(),
-        _ => {
-            order.extend(1..=name_count as u8);
-            order = filter_5.to_smallvec();
-            for index in 6..=name_count {
-                order.push(index as u8)
+    config: &Config,
+    matchclasses: &[Matchclass],
+    backend: &dyn Backend
+)
+-> DnsLrResult<Option<Matchclass>> {
+    let domain_name = query_name(request);
+    let found = find_match(&domain_name, request.query().query_type(), config, matchclasses, backend).await?;
+
+    if let Some((domain_to_check, matchclass)) = found {
+        if matchclass.is_allow {
+            info!("{}: Request n°{}: {} is allowed by {}", config.daemon_id, request.id(), domain_to_check, matchclass.name);
+            return Ok(Some(matchclass))
+        }
+        info!("{}: Request n°{}: {} has matched {}", config.daemon_id, request.id(), domain_to_check, matchclass.name);
+        return Ok(Some(matchclass))
+    }
+
+    return Ok(None)
+}
+
+// The first matchclass that matches the normalized name or one of its ancestors,
+// the NXDOMAIN list first, then the allow matchclasses, then the block ones
+pub async fn find_match (
+    domain_name: &str,
+    qtype: RecordType,
+    config: &Config,
+    matchclasses: &[Matchclass],
+    backend: &dyn Backend
+)
+-> DnsLrResult<Option<(String, Matchclass)>> {
+    let labels: SmallVec<[&str; 5]> = domain_name.trim_end_matches('.').split('.').collect();
+    let label_count = labels.len();
+
+    // The exact name is always checked, its ancestors are then checked from the TLD down
+    let mut domains_to_check: SmallVec<[String; 5]> = smallvec![domain_name.to_string()];
+    if config.subdomain_matching || config.wildcard_matching || matchclasses.iter().any(|matchclass| matchclass.match_mode == MatchMode::Suffix) {
+        for kept_labels in (1..label_count).take(MAX_ANCESTOR_LOOKUPS) {
+            let mut ancestor = labels[label_count - kept_labels..].join(".");
+            ancestor.push('.');
+            domains_to_check.push(ancestor)
+        }
+    }
+    // A wildcard key holds the name with a leading dot, "ads:.example.com." matches example.com. and all its subdomains
+    let wildcards_to_check: SmallVec<[String; 5]> = match config.wildcard_matching {
+        true => domains_to_check.iter().map(|domain_to_check| format!(".{}", domain_to_check)).collect(),
+        false => SmallVec::new()
+    };
+
+    // Allow matchclasses are all checked before the block ones so they override any of them
+    let mut candidates: Vec<(&str, &Matchclass)> = Vec::new();
+    for (is_nxdomain, is_allow) in [(true, false), (false, true), (false, false)] {
+        let group: SmallVec<[&Matchclass; 4]> = matchclasses.iter()
+            .filter(|matchclass| matchclass.is_nxdomain == is_nxdomain && matchclass.is_allow == is_allow && matchclass.qtypes.covers(qtype))
+            .collect();
+        for (index, domain_to_check) in domains_to_check.iter().enumerate() {
+            for matchclass in group.iter() {
+                let is_checked = match matchclass.match_mode {
+                    MatchMode::Exact => index == 0 || config.subdomain_matching,
+                    MatchMode::Suffix => true,
+                    MatchMode::Contains(_) => index == 0
+                };
+                if is_checked {
+                    candidates.push((domain_to_check, matchclass))
+                }
             }
         }
+        // The wildcards come after the plain keys of the group, an exact entry names the match first
+        for wildcard_to_check in wildcards_to_check.iter() {
+            for matchclass in group.iter().filter(|matchclass| !matches!(matchclass.match_mode, MatchMode::Contains(_))) {
+                candidates.push((wildcard_to_check, matchclass))
+            }
+        }
+    }
+
+    // Every key candidate is checked in a single round trip, contains candidates are checked here
+    let fullmatches: Vec<String> = candidates.iter()
+        .filter(|(_, matchclass)| !matches!(matchclass.match_mode, MatchMode::Contains(_)))
+        .map(|(domain_to_check, matchclass)| redis_mod::match_key(&matchclass.name, domain_to_check))
+        .collect();
+    let mut found = backend.exists_batch(&fullmatches).await?.into_iter();
+
+    // The first candidate found decides
+    let first_found = candidates.into_iter().find(|(domain_to_check, matchclass)| match &matchclass.match_mode {
+        MatchMode::Contains(tokens) => tokens.iter().any(|token| domain_to_check.contains(token.as_str())),
+        MatchMode::Exact | MatchMode::Suffix => found.next().unwrap_or(false)
+    });
+    return Ok(first_found.map(|(domain_to_check, matchclass)| (domain_to_check.to_string(), matchclass.clone())))
+}
+
+// The name a debug query asks about, "ads.example.com._dnsliar." asks about "ads.example.com."
+pub fn debug_query_name (
+    request: &Request
+)
+-> Option<String> {
+    if request.query().query_type() != RecordType::TXT {
+        return None
+    }
+    // Names compare case-insensitively, _DNSLIAR. is the debug zone too
+    let name = request.query().name().to_string().to_ascii_lowercase();
+    let checked_name = name.strip_suffix(DEBUG_ZONE)?;
+
+    return normalize_name(checked_name)
+}
+
+// Answers a debug query with what a query on the name would get, without resolving it
+pub async fn debug_answer (
+    request: &Request,
+    config: &Config,
+    matchclasses: &[Matchclass],
+    checked_name: &str,
+    backend: &dyn Backend
+)
+-> DnsLrResult<Record> {
+    let found = find_match(checked_name, RecordType::A, config, matchclasses, backend).await?;
+    let verdict = match found {
+        Some((domain_to_check, matchclass)) if matchclass.is_allow => format!("allowed by {} on {}", matchclass.name, domain_to_check),
+        Some((domain_to_check, matchclass)) => format!("matched {} on {}", matchclass.name, domain_to_check),
+        None => "unmatched".to_string()
+    };
+
+    return Ok(Record::from_rdata(request.query().name().into(), 0, RData::TXT(TXT::new(vec![verdict]))))
+}
+
+// The whole pool, starting at a different address for each rotation
+fn rotated <T: Copy> (
+    pool: &[T],
+    rotation: usize
+)
+-> impl Iterator<Item = T> + '_ {
+    return pool.iter().cycle().skip(rotation % pool.len().max(1)).take(pool.len()).copied()
+}
+
+// Picks a sink with a chance proportional to its weight, uniformly when every weight is 0
+fn weighted_pick <T: Copy> (
+    sinks: &[(T, u32)]
+)
+-> Option<T> {
+    return weighted_pick_with(sinks, &mut rand::thread_rng())
+}
+
+fn weighted_pick_with <T: Copy> (
+    sinks: &[(T, u32)],
+    rng: &mut impl Rng
+)
+-> Option<T> {
+    if sinks.is_empty() {
+        return None
+    }
+    let total: u64 = sinks.iter().map(|(_, weight)| *weight as u64).sum();
+    if total == 0 {
+        return Some(sinks[rng.gen_range(0..sinks.len())].0)
     }
 
-    let (blackhole_ipv4, blackhole_ipv6) = config.blackhole_ips.unwrap();
-    let matchclasses = config.matchclasses.clone().unwrap();
-
-    let names: SmallVec<[&str; 5]> = names.collect();
-    for index in order {
-        let mut domain_to_check = names[name_count - (index as usize)..name_count].join(".");
-        domain_to_check.push('.');
-
-        for matchclass in matchclasses.iter() {
-            match redis_mod::exists(
-                &mut redis_manager,
-                format!("{}:{}", matchclass, domain_to_check),
-                request.query().query_type()
-            ).await {
-                Ok(ok) => {
-                    if ok {
-                        //answer IPs that respond a reset
-                        info!("{}: Request n°{}: {} has matched {}", config.daemon_id, request.id(), domain_to_check, matchclass);
-
-                        let rdata = match request.query().query_type() {
-                            RecordType::A => RData::A(blackhole_ipv4),
-                            RecordType::AAAA => RData::AAAA(blackhole_ipv6),
-                            _ => unreachable!()
-                        };
-                        return Ok((vec![Record::from_rdata(request.query().name().into(), 3600, rdata)], header))
-                    };
+    let mut point = rng.gen_range(0..total);
+    for (sink, weight) in sinks {
+        if point < *weight as u64 {
+            return Some(*sink)
+        }
+        point -= *weight as u64
+    }
+    return None
+}
+
+// rotation picks the blackhole IP answered first when a family has several
+pub fn lie (
+    request: &Request,
+    config: &Config,
+    header: Header,
+    matchclass: &Matchclass,
+    rotation: usize
+)
+-> (Vec<Record>, Header) {
+    let mut header = unsigned(header);
+    if matchclass.is_nxdomain {
+        header.set_response_code(ResponseCode::NXDomain);
+        return (vec![], header)
+    }
+    if matchclass.is_redirect {
+        let rdatas: Vec<RData> = match request.query().query_type() {
+            RecordType::A => matchclass.blackhole_ipv4s.iter().map(|(ipv4, _)| RData::A(*ipv4)).collect(),
+            RecordType::AAAA => matchclass.blackhole_ipv6s.iter().map(|(ipv6, _)| RData::AAAA(*ipv6)).collect(),
+            _ => vec![]
+        };
+        let answers = rdatas.into_iter()
+            .map(|rdata| Record::from_rdata(request.query().name().into(), config.blackhole_ttl, rdata))
+            .collect();
+        return (answers, header)
+    }
+    match svcb_filter_mode(request, config) {
+        Some(SvcbFilterMode::Nodata) => return (vec![], header),
+        Some(SvcbFilterMode::Nxdomain) => {
+            header.set_response_code(ResponseCode::NXDomain);
+            return (vec![], header)
+        },
+        Some(SvcbFilterMode::Refused) => {
+            header.set_response_code(ResponseCode::Refused);
+            return (vec![], header)
+        },
+        None => ()
+    }
+    match config.filter_mode {
+        FilterMode::BlackholeIp => {
+            let Some((blackhole_ipv4s, blackhole_ipv6s)) = &config.blackhole_ips else {
+                return (vec![], header)
+            };
+            // The blackhole IPs of a matchclass replace the pool of their family
+            let rdatas: Vec<RData> = match request.query().query_type() {
+                RecordType::A if !blackhole_ipv4s.is_empty() => match weighted_pick(&matchclass.blackhole_ipv4s) {
+                    Some(blackhole_ipv4) => vec![RData::A(blackhole_ipv4)],
+                    None => rotated(blackhole_ipv4s, rotation).map(RData::A).collect()
+                },
+                RecordType::AAAA if !blackhole_ipv6s.is_empty() => match weighted_pick(&matchclass.blackhole_ipv6s) {
+                    Some(blackhole_ipv6) => vec![RData::AAAA(blackhole_ipv6)],
+                    None => rotated(blackhole_ipv6s, rotation).map(RData::AAAA).collect()
                 },
-                Err(error) => return Err(error)
+                // There is no address to lie with for the other types or for a family without blackhole,
+                // so the name is answered without records
+                _ => return (vec![], header)
             };
+            let answers = rdatas.into_iter()
+                .map(|rdata| Record::from_rdata(request.query().name().into(), config.blackhole_ttl, rdata))
+                .collect();
+            return (answers, header)
+        },
+        FilterMode::Nxdomain => header.set_response_code(ResponseCode::NXDomain),
+        FilterMode::Refused => header.set_response_code(ResponseCode::Refused)
+    };
+
+    return (vec![], header)
+}
+
+// The DNSSEC flags of an answer made up here rather than validated upstream, whether DO was set or not
+// It has no RRSIG, so AD is cleared even when DNSSEC validation is on and the client asked for it
+// CD is kept as the client set it like RFC 4035 requires, a client validating by itself then sees an unsigned
+// answer for a signed zone as bogus, which blocks the name on its side too
+pub fn unsigned (
+    mut header: Header
+)
+-> Header {
+    header.set_authentic_data(false);
+    return header
+}
+
+// Minimal SOA for the authority section of NXDOMAIN lies, its minimum is the negative caching TTL
+pub fn negative_soa (
+    request: &Request,
+    config: &Config
+)
+-> Record {
+    let mname = Name::from_ascii(&config.soa_mname).unwrap_or_else(|_| Name::root());
+    let rname = Name::from_ascii(&config.soa_rname).unwrap_or_else(|_| Name::root());
+    let soa = SOA::new(mname, rname, config.soa_serial, SOA_REFRESH, SOA_RETRY, SOA_EXPIRE, config.soa_minimum_ttl);
+
+    return Record::from_rdata(soa_owner(&request.query().name().into()), config.soa_minimum_ttl, RData::SOA(soa))
+}
+
+// The SOA of a name said not to exist cannot be owned by that name, the parent stands for the apex of its zone
+fn soa_owner (
+    name: &Name
+)
+-> Name {
+    return match name.is_root() {
+        true => Name::root(),
+        false => name.base_name()
+    }
+}
+
+// True for a PTR query on a blackhole IP, whether global or of any matchclass
+pub fn is_blackhole_ptr (
+    request: &Request,
+    config: &Config
+)
+-> bool {
+    let (RecordType::PTR, Some((blackhole_ipv4s, blackhole_ipv6s))) = (request.query().query_type(), &config.blackhole_ips) else {
+        return false
+    };
+    let Ok(ip) = Name::from(request.query().name()).parse_arpa_name() else {
+        return false
+    };
+
+    let ip = ip.addr();
+    let mut matchclasses = config.matchclasses.iter().flatten().chain(config.policies.values().flatten());
+    return match ip {
+        IpAddr::V4(ipv4) => blackhole_ipv4s.contains(&ipv4) || matchclasses.any(|matchclass| matchclass.blackhole_ipv4s.iter().any(|(sink, _)| *sink == ipv4)),
+        IpAddr::V6(ipv6) => blackhole_ipv6s.contains(&ipv6) || matchclasses.any(|matchclass| matchclass.blackhole_ipv6s.iter().any(|(sink, _)| *sink == ipv6))
+    }
+}
+
+pub fn blackhole_ptr (
+    request: &Request,
+    config: &Config
+)
+-> Record {
+    let ptr = Name::from_ascii(&config.blackhole_ptr).unwrap_or_else(|_| Name::root());
+
+    return Record::from_rdata(request.query().name().into(), config.blackhole_ttl, RData::PTR(ptr))
+}
+
+// RFC 8482 answer to an ANY query, a single synthesized HINFO instead of every record of the name
+// The queries of a qtype the resolver does not look up get it too with unsupported_qtypes_hinfo
+pub fn any_hinfo (
+    request: &Request
+)
+-> Record {
+    let hinfo = HINFO::new(ANY_HINFO_CPU.to_string(), String::new());
+
+    return Record::from_rdata(request.query().name().into(), ANY_HINFO_TTL, RData::HINFO(hinfo))
+}
+
+// Whether the record is the HINFO of an RFC 8482 answer, synthesized by any_hinfo or by the forwarders
+pub fn is_rfc8482_hinfo (
+    record: &Record
+)
+-> bool {
+    return matches!(record.data(), Some(RData::HINFO(hinfo)) if hinfo.cpu() == ANY_HINFO_CPU.as_bytes())
+}
+
+// The TXT answer to a CHAOS query on the version or the identity of the server
+// None for any other CHAOS query and for the identity when chaos_hide_id is set, those are refused
+pub fn chaos_answer (
+    request: &Request,
+    config: &Config
+)
+-> Option<Record> {
+    if request.query().query_type() != RecordType::TXT {
+        return None
+    }
+    let name = request.query().name().to_string().to_ascii_lowercase();
+    let text = match name.as_str() {
+        name if CHAOS_VERSION_NAMES.contains(&name) => config.chaos_version.clone(),
+        name if CHAOS_ID_NAMES.contains(&name) && !config.chaos_hide_id => config.daemon_id.clone(),
+        _ => return None
+    };
+
+    let mut record = Record::from_rdata(request.query().name().into(), 0, RData::TXT(TXT::new(vec![text])));
+    record.set_dns_class(DNSClass::CH);
+    return Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+    use trust_dns_proto::{
+        op::{Message, Query},
+        serialize::binary::BinDecodable
+    };
+    use trust_dns_server::{authority::MessageRequest, server::Protocol};
+
+    #[test]
+    fn weighted_pick_follows_the_weights () {
+        let mut rng = StdRng::seed_from_u64(63);
+        let sinks = [('a', 1), ('b', 3), ('c', 0)];
+        let mut counts = [0u32; 3];
+        for _ in 0..40000 {
+            match weighted_pick_with(&sinks, &mut rng) {
+                Some('a') => counts[0] += 1,
+                Some('b') => counts[1] += 1,
+                Some('c') => counts[2] += 1,
+                other => panic!("unexpected pick: {:?}", other)
+            }
         }
+        // 1/4 and 3/4 of the picks, within 2 points
+        assert!((9200..=10800).contains(&counts[0]), "a was picked {} times", counts[0]);
+        assert!((29200..=30800).contains(&counts[1]), "b was picked {} times", counts[1]);
+        assert_eq!(counts[2], 0);
+    }
+
+    #[test]
+    fn weighted_pick_is_uniform_when_every_weight_is_zero () {
+        let mut rng = StdRng::seed_from_u64(63);
+        let sinks = [('a', 0), ('b', 0)];
+        let picked_a = (0..10000).filter(|_| weighted_pick_with(&sinks, &mut rng) == Some('a')).count();
+        assert!((4600..=5400).contains(&picked_a), "a was picked {} times", picked_a);
+    }
+
+    #[test]
+    fn weighted_pick_handles_a_single_sink_and_none () {
+        let mut rng = StdRng::seed_from_u64(63);
+        assert!((0..100).all(|_| weighted_pick_with(&[('a', 5)], &mut rng) == Some('a')));
+        assert!((0..100).all(|_| weighted_pick_with(&[('a', 0)], &mut rng) == Some('a')));
+        assert_eq!(weighted_pick_with::<char>(&[], &mut rng), None);
+    }
+
+    fn txt_request (
+        name: &str
+    )
+    -> Request {
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_ascii(name).unwrap(), RecordType::TXT));
+        let message = MessageRequest::from_bytes(&message.to_vec().unwrap()).unwrap();
+        return Request::new(message, ([127, 0, 0, 1], 5353).into(), Protocol::Udp)
+    }
+
+    #[test]
+    fn debug_query_name_ignores_the_case () {
+        assert_eq!(debug_query_name(&txt_request("Ads.Example.com._DNSLIAR.")), Some("ads.example.com.".to_string()));
+        assert_eq!(debug_query_name(&txt_request("ads.example.com._dnsliar.")), Some("ads.example.com.".to_string()));
+        assert_eq!(debug_query_name(&txt_request("ads.example.com.")), None);
+    }
+
+    #[test]
+    fn is_rfc8482_hinfo_only_takes_the_rfc8482_cpu () {
+        let name = Name::from_ascii("example.com.").unwrap();
+        let rfc8482 = Record::from_rdata(name.clone(), ANY_HINFO_TTL, RData::HINFO(HINFO::new(ANY_HINFO_CPU.to_string(), String::new())));
+        let other = Record::from_rdata(name.clone(), ANY_HINFO_TTL, RData::HINFO(HINFO::new("x86".to_string(), "linux".to_string())));
+        let address = Record::from_rdata(name, ANY_HINFO_TTL, RData::A([192, 0, 2, 1].into()));
+        assert!(is_rfc8482_hinfo(&rfc8482));
+        assert!(!is_rfc8482_hinfo(&other));
+        assert!(!is_rfc8482_hinfo(&address));
     }
 
-    return resolver_mod::get_answers(request, header, resolver).await
+    #[test]
+    fn soa_owner_is_the_parent_of_the_name () {
+        let owner = |name: &str| soa_owner(&Name::from_ascii(name).unwrap()).to_ascii();
+        assert_eq!(owner("ads.example.com."), "example.com.");
+        assert_eq!(owner("com."), ".");
+        assert_eq!(owner("."), ".");
+    }
+
+    #[test]
+    fn normalize_name_lowercases_mixed_case () {
+        assert_eq!(normalize_name("Ads.DoubleClick.NET"), Some("ads.doubleclick.net.".to_string()));
+    }
+
+    #[test]
+    fn normalize_name_keeps_a_single_trailing_dot () {
+        assert_eq!(normalize_name("example.com."), Some("example.com.".to_string()));
+        assert_eq!(normalize_name("example.com"), Some("example.com.".to_string()));
+    }
+
+    #[test]
+    fn normalize_name_converts_u_labels_to_punycode () {
+        assert_eq!(normalize_name("Bücher.Example"), Some("xn--bcher-kva.example.".to_string()));
+    }
+
+    #[test]
+    fn normalize_name_rejects_invalid_names () {
+        assert_eq!(normalize_name(&format!("{}.example.com", "a".repeat(64))), None);
+        assert_eq!(normalize_name("a..example.com"), None);
+        assert_eq!(normalize_name(""), None);
+        assert_eq!(normalize_name("."), None);
+    }
 }
@@ -0,0 +1,150 @@
+use crate::enums_structs::{Stats, LATENCY_BUCKETS_MS};
+use crate::top_blocked_mod::TOP_BLOCKED;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener
+};
+use tracing::{debug, Instrument};
+use std::{
+    fmt::Write,
+    fs,
+    io,
+    sync::{Arc, atomic::Ordering}
+};
+
+pub async fn serve (
+    listener: TcpListener,
+    stats: Arc<Stats>,
+    daemon_id: String
+) {
+    loop {
+        let Ok((mut stream, peer)) = listener.accept().await else {
+            continue
+        };
+        let stats = Arc::clone(&stats);
+        let daemon_id = daemon_id.clone();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            let Ok(read) = stream.read(&mut buffer).await else {
+                return
+            };
+
+            let request = String::from_utf8_lossy(&buffer[..read]);
+            let response = match request.split_whitespace().nth(1) {
+                Some("/metrics") => {
+                    let body = render(&stats, &daemon_id);
+                    format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+                },
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            };
+
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                debug!("{}: Could not answer the metrics request from {}", daemon_id, peer)
+            }
+        }.in_current_span());
+    }
+}
+
+fn render (
+    stats: &Stats,
+    daemon_id: &str
+)
+-> String {
+    let mut body = String::new();
+
+    let counters = [
+        ("dnsliar_queries_total", "Queries received", &stats.queries),
+        ("dnsliar_filtered_total", "Queries answered with a lie", &stats.filtered),
+        ("dnsliar_forwarded_total", "Queries sent to the forwarders", &stats.forwarded),
+        ("dnsliar_nxdomains_total", "Queries answered with NXDOMAIN", &stats.nxdomains),
+        ("dnsliar_forward_errors_total", "Forwarded queries that failed", &stats.forward_errors),
+        ("dnsliar_redis_errors_total", "Redis lookups that failed", &stats.redis_errors),
+        ("dnsliar_rate_limited_total", "Queries refused by the rate limiter", &stats.rate_limited),
+        ("dnsliar_static_answers_total", "Queries answered from the static records", &stats.static_answers),
+        ("dnsliar_unauthorized_total", "Queries refused because the client is not in allowed_networks", &stats.unauthorized),
+        ("dnsliar_overloaded_total", "Queries dropped or refused because max_inflight was reached", &stats.overloaded),
+        ("dnsliar_rrl_limited_total", "UDP responses truncated or dropped by response rate limiting", &stats.rrl_limited),
+        ("dnsliar_match_cache_hits_total", "Match decisions found in the match cache", &stats.match_cache_hits),
+        ("dnsliar_match_cache_misses_total", "Match decisions looked up in Redis with the match cache on", &stats.match_cache_misses),
+        ("dnsliar_coalesced_total", "Forwarded queries answered with the answer to an identical query in flight", &stats.coalesced),
+        ("dnsliar_malformed_dropped_total", "Malformed queries dropped without an answer", &stats.malformed_dropped),
+        ("dnsliar_policy_service_errors_total", "Queries the policy service failed to decide on", &stats.policy_service_errors)
+    ];
+    for (name, help, counter) in counters {
+        let _ = writeln!(body, "# HELP {} {}", name, help);
+        let _ = writeln!(body, "# TYPE {} counter", name);
+        let _ = writeln!(body, "{}{{daemon_id=\"{}\"}} {}", name, daemon_id, counter.load(Ordering::Relaxed));
+    }
+
+    let name = "dnsliar_responses_total";
+    let _ = writeln!(body, "# HELP {} Responses by response code", name);
+    let _ = writeln!(body, "# TYPE {} counter", name);
+    for (rcode, count) in stats.response_codes.labeled() {
+        let _ = writeln!(body, "{}{{daemon_id=\"{}\",rcode=\"{}\"}} {}", name, daemon_id, rcode, count);
+    }
+
+    // An estimate, only the most filtered domains are followed so that the series stay few
+    let name = "dnsliar_top_blocked";
+    let _ = writeln!(body, "# HELP {} Approximate filtered queries of the {} most filtered domains", name, TOP_BLOCKED);
+    let _ = writeln!(body, "# TYPE {} gauge", name);
+    for (domain, count) in stats.top_blocked.top(TOP_BLOCKED) {
+        let _ = writeln!(body, "{}{{daemon_id=\"{}\",domain=\"{}\"}} {}", name, daemon_id, escape_label(&domain), count);
+    }
+
+    let name = "dnsliar_resolver_latency_seconds";
+    let _ = writeln!(body, "# HELP {} Time spent waiting on the forwarders", name);
+    let _ = writeln!(body, "# TYPE {} histogram", name);
+    let mut cumulative: u64 = 0;
+    for (index, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+        cumulative += stats.resolver_latency.buckets[index].load(Ordering::Relaxed);
+        let _ = writeln!(body, "{}_bucket{{daemon_id=\"{}\",le=\"{}\"}} {}", name, daemon_id, *bound as f64 / 1000.0, cumulative);
+    }
+    let count = stats.resolver_latency.count.load(Ordering::Relaxed);
+    let _ = writeln!(body, "{}_bucket{{daemon_id=\"{}\",le=\"+Inf\"}} {}", name, daemon_id, count);
+    let _ = writeln!(body, "{}_sum{{daemon_id=\"{}\"}} {}", name, daemon_id, stats.resolver_latency.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+    let _ = writeln!(body, "{}_count{{daemon_id=\"{}\"}} {}", name, daemon_id, count);
+
+    return body
+}
+
+// Query names may carry any byte, the label format only allows these three escaped
+fn escape_label (
+    value: &str
+)
+-> String {
+    return value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Writes the counters as JSON next to the target then renames it, so a reader never sees a partial file
+pub fn write_stats_file (
+    stats: &Stats,
+    path: &str,
+    daemon_id: &str
+)
+-> io::Result<()> {
+    let body = serde_json::json!({
+        "daemon_id": daemon_id,
+        "queries": stats.queries.load(Ordering::Relaxed),
+        "filtered": stats.filtered.load(Ordering::Relaxed),
+        "forwarded": stats.forwarded.load(Ordering::Relaxed),
+        "nxdomains": stats.nxdomains.load(Ordering::Relaxed),
+        "forward_errors": stats.forward_errors.load(Ordering::Relaxed),
+        "redis_errors": stats.redis_errors.load(Ordering::Relaxed),
+        "rate_limited": stats.rate_limited.load(Ordering::Relaxed),
+        "static_answers": stats.static_answers.load(Ordering::Relaxed),
+        "unauthorized": stats.unauthorized.load(Ordering::Relaxed),
+        "overloaded": stats.overloaded.load(Ordering::Relaxed),
+        "rrl_limited": stats.rrl_limited.load(Ordering::Relaxed),
+        "match_cache_hits": stats.match_cache_hits.load(Ordering::Relaxed),
+        "match_cache_misses": stats.match_cache_misses.load(Ordering::Relaxed),
+        "coalesced": stats.coalesced.load(Ordering::Relaxed),
+        "malformed_dropped": stats.malformed_dropped.load(Ordering::Relaxed),
+        "policy_service_errors": stats.policy_service_errors.load(Ordering::Relaxed)
+    });
+
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, body.to_string())?;
+    return fs::rename(&tmp_path, path)
+}
@@ -0,0 +1,106 @@
+use crate::enums_structs::{DnsLrResult, WrappedErrors, ErrorKind, PolicyService};
+
+use lru::LruCache;
+use reqwest::Client;
+use serde::Deserialize;
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::Instant
+};
+use trust_dns_proto::rr::RecordType;
+
+// Decisions kept at once, whatever the number of clients and names asking
+const CACHE_SIZE: usize = 10000;
+
+// The client IP, the normalized query name and the qtype, a service may decide per client
+type Key = (IpAddr, String, RecordType);
+
+// What the service answers for a query no matchclass decided
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Decision {
+    Allow,
+    Block,
+    // Answered with these addresses, those of the queried family
+    Redirect(Vec<IpAddr>)
+}
+
+// {"action": "allow" | "block" | "redirect", "addresses": ["192.0.2.1", "2001:db8::1"]}
+#[derive(Deserialize)]
+struct Answer {
+    action: String,
+    #[serde(default)]
+    addresses: Vec<IpAddr>
+}
+
+// Asks the policy_service_url with a POST of {"client": "192.0.2.10", "qname": "ads.example.com.", "qtype": "A"}
+pub struct PolicyServiceClient {
+    client: Client,
+    cache: Mutex<LruCache<Key, (Decision, Instant)>>
+}
+impl PolicyServiceClient {
+    pub fn new ()
+    -> PolicyServiceClient {
+        return PolicyServiceClient {
+            client: Client::new(),
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap_or(NonZeroUsize::MIN)))
+        }
+    }
+
+    // Failures are never cached, the next query on the name asks the service again
+    pub async fn decide (
+        &self,
+        service: &PolicyService,
+        client: IpAddr,
+        qname: &str,
+        qtype: RecordType
+    )
+    -> DnsLrResult<Decision> {
+        let key = (client, qname.to_string(), qtype);
+        if let Some((decision, expires)) = self.cache.lock().unwrap().get(&key) {
+            if *expires > Instant::now() {
+                return Ok(decision.clone())
+            }
+        }
+
+        let decision = self.ask(service, client, qname, qtype).await
+            .map_err(|reason| WrappedErrors::DNSlrError(ErrorKind::PolicyServiceFailed(reason)))?;
+        if !service.cache_ttl.is_zero() {
+            self.cache.lock().unwrap().put(key, (decision.clone(), Instant::now() + service.cache_ttl));
+        }
+        return Ok(decision)
+    }
+
+    async fn ask (
+        &self,
+        service: &PolicyService,
+        client: IpAddr,
+        qname: &str,
+        qtype: RecordType
+    )
+    -> Result<Decision, String> {
+        let body = serde_json::json!({
+            "client": client.to_string(),
+            "qname": qname,
+            "qtype": qtype.to_string()
+        });
+        let response = self.client.post(&service.url)
+            .timeout(service.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send().await
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| error.to_string())?;
+        let text = response.text().await.map_err(|error| error.to_string())?;
+
+        let answer = serde_json::from_str::<Answer>(&text).map_err(|error| error.to_string())?;
+        return match answer.action.as_str() {
+            "allow" => Ok(Decision::Allow),
+            "block" => Ok(Decision::Block),
+            "redirect" if !answer.addresses.is_empty() => Ok(Decision::Redirect(answer.addresses)),
+            "redirect" => Err("a redirect came without addresses".to_string()),
+            action => Err(format!("action: {} is not allow, block or redirect", action))
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use lru::LruCache;
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::Instant
+};
+
+// Caps the memory used by the buckets when facing a flood of spoofed source IPs
+const MAX_TRACKED_CLIENTS: usize = 65536;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant
+}
+
+pub struct RateLimiter {
+    buckets: Mutex<LruCache<IpAddr, Bucket>>
+}
+impl RateLimiter {
+    pub fn new ()
+    -> RateLimiter {
+        return RateLimiter {
+            buckets: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_TRACKED_CLIENTS).unwrap()))
+        }
+    }
+
+    pub fn check (
+        &self,
+        client: IpAddr,
+        capacity: u32,
+        refill_per_sec: u32
+    )
+    -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_mut(client, || Bucket {
+            tokens: capacity as f64,
+            last_refill: now
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_per_sec as f64).min(capacity as f64);
+        bucket.last_refill = now;
+
+        if bucket.tokens < 1.0 {
+            return false
+        }
+        bucket.tokens -= 1.0;
+        return true
+    }
+}
@@ -1,145 +1,1473 @@
-use crate::{
-    enums_structs::{Config, DnsLrResult, WrappedErrors, ErrorKind},
-    CONFILE
-};
-
-use redis::{
-    aio::{ConnectionManager, ConnectionLike},
-    Client
-};
-
-use tracing::{info, error, warn};
-use std::{
-    net::{SocketAddr, Ipv4Addr, Ipv6Addr}
-};
-
-use trust_dns_client::rr::RecordType;
-
-pub async fn build_manager ()
--> DnsLrResult<ConnectionManager> {
-    let client = Client::open(format!("redis://{}/", &CONFILE.redis_address)).expect("Error probing the Redis server");
-    info!("{}: Redis server probe successful", &CONFILE.daemon_id);
-
-    let manager = client.get_tokio_connection_manager().await.expect("Error creating the connection manager");
-    info!("{}: Connection to Redis successful", &CONFILE.daemon_id);
-
-    return Ok(manager)
-}
-
-pub async fn build_config (
-    manager: &mut ConnectionManager
-)
--> DnsLrResult<Config> {
-    let mut config = Config {
-        daemon_id: CONFILE.daemon_id.clone(),
-        forwarders: vec![],
-        binds : vec![],
-        is_filtering: false,
-        matchclasses: None,
-        blackhole_ips: None
-    };
-
-    let tmp_blackhole_ips = get(manager, "blackhole_ips", &config.daemon_id).await.expect("Error fetching blackhole_ips");
-    let blackhole_ips_count = tmp_blackhole_ips.clone().iter().count();
-    if blackhole_ips_count != 2 {
-        warn!("{}: Amount of blackhole_ips received were not 2 (must have a v4 and v6)", config.daemon_id);
-        warn!("{}: The server will not filter any request and so will not lie", config.daemon_id)
-    } else {
-        config.blackhole_ips = Some((
-            tmp_blackhole_ips[0].parse::<Ipv4Addr>().expect("Error parsing blackhole_ipv4"),
-            tmp_blackhole_ips[1].parse::<Ipv6Addr>().expect("Error parsing blackhole_ipv6")
-        ));
-        info!("{}: Blackhole_ips received are valid", config.daemon_id);
-
-        let tmp_matchclasses = get(manager, "matchclasses", &config.daemon_id).await.expect("Error fetching matchclasses");
-        let matchclasses_count = tmp_matchclasses.clone().iter().count();
-        if matchclasses_count == 0 {
-            warn!("{}: No matchclass received", config.daemon_id);
-            warn!("{}: The server will not filter any request and so will not lie", config.daemon_id)
-        } else {
-            config.is_filtering = true;
-            config.matchclasses = Some(tmp_matchclasses);
-
-            info!("{}: Received {} matchclasses", config.daemon_id, matchclasses_count)
-        }
-    }
-
-    let ser_forwarders = get(manager, "forwarders", &config.daemon_id).await.expect("Error fetching forwarders");
-    let forwarders_count = ser_forwarders.clone().iter().count() as u8;
-    if forwarders_count == 0 {
-        error!("{}: No forwarder was received", config.daemon_id);
-        return Err(WrappedErrors::DNSlrError(ErrorKind::SetupForwardersError))
-    }
-    info!("{}: Received {} forwarders", config.daemon_id, forwarders_count);
-
-    let mut valid_forwarder_count: u8 = 0;
-    for forwarder in ser_forwarders {
-        config.forwarders.push(
-            match forwarder.parse::<SocketAddr>() {
-                Ok(ok) => ok,
-                Err(_) => {
-                    warn!("{}: forwarder: {} is not valid", config.daemon_id, forwarder);
-                    continue
-                }
-            }
-        );
-        valid_forwarder_count += 1
-    }
-    if valid_forwarder_count == forwarders_count {
-        info!("{}: all {} forwarders are valid", config.daemon_id, valid_forwarder_count)
-    } else if valid_forwarder_count < forwarders_count {
-        warn!("{}: {} out of {} forwarders are valid", config.daemon_id, valid_forwarder_count, forwarders_count)
-    } else if valid_forwarder_count == 0 {
-        error!("{}: No forwarder is valid", config.daemon_id);
-        return Err(WrappedErrors::DNSlrError(ErrorKind::SetupForwardersError))
-    }
-
-    config.binds = get(manager, "binds", &config.daemon_id).await.expect("Error fetching binds");
-    let bind_count = config.binds.clone().iter().count() as u32;
-    if bind_count == 0 {
-        error!("{}: No bind received", config.daemon_id);
-        return Err(WrappedErrors::DNSlrError(ErrorKind::SetupBindingError))
-    }
-    info!("{}: Received {} binds", config.daemon_id, bind_count);
-
-    return Ok(config)
-}
-
-pub async fn exists (
-    manager: &mut ConnectionManager,
-    fullmatch: String,
-    qtype: RecordType
-)
--> DnsLrResult<bool> {
-    let qtype: &str = match qtype {
-        RecordType::A => "A", 
-        RecordType::AAAA => "AAAA",
-        _ => unreachable!()
-    };
-
-    let ser_answer = manager.req_packed_command(
-        redis::Cmd::new()
-            .arg("EXISTS")
-            .arg(fullmatch)
-            .arg(qtype))
-            .await?;
-    
-    let deser_answer = redis::FromRedisValue::from_redis_value(&ser_answer)?;
-    return Ok(deser_answer)
-}
-
-pub async fn get (
-    manager: &mut ConnectionManager,
-    kind: &str,
-    daemon_id: &String
-)
--> DnsLrResult<Vec<String>> {
-    let ser_answer = manager.req_packed_command(
-        redis::Cmd::new()
-            .arg("HKEYS")
-            .arg(format!("{}_{}", kind, daemon_id)))
-            .await?;
-
-    let deser_answer = redis::FromRedisValue::from_redis_value(&ser_answer)?;
-    return Ok(deser_answer)
-}
+use crate::{
+    socket_mod,
+    matching,
+    resolver_mod,
+    backend_mod::Backend,
+    enums_structs::{AnyQueries, Config, DnsLrResult, WrappedErrors, ErrorKind, ExtendedError, FilterMode, Forwarder, ForwarderProtocol, ForwarderStrategy, Matchclass, MatchMode, Network, PolicyService, QtypeFilter, RedisFailureMode, Stats, SvcbFilterMode},
+    CONFILE
+};
+
+use redis::{
+    aio::{ConnectionManager, ConnectionLike, PubSub},
+    Client, ConnectionInfo, ConnectionAddr, RedisConnectionInfo
+};
+
+use tracing::{info, error, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}},
+    net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+use trust_dns_client::rr::{Name, RecordType};
+
+const DEFAULT_REDIS_PORT: u16 = 6379;
+const DEFAULT_REDIS_MAX_ATTEMPTS: u32 = 5;
+const DEFAULT_REDIS_RETRY_DELAY_MS: u64 = 500;
+const MAX_REDIS_POOL_SIZE: usize = 64;
+const DEFAULT_ECS_PREFIX_V4: u8 = 24;
+const DEFAULT_ECS_PREFIX_V6: u8 = 56;
+const DEFAULT_BLACKHOLE_TTL: u32 = 3600;
+const DEFAULT_SOA_MINIMUM_TTL: u32 = 300;
+const DEFAULT_SOA_MNAME: &str = "dnsliar.";
+const DEFAULT_SOA_RNAME: &str = "hostmaster.dnsliar.";
+const DEFAULT_BLACKHOLE_PTR: &str = "blocked.dnsliar.local.";
+const DEFAULT_STATIC_TTL: u32 = 3600;
+const DEFAULT_CHAOS_VERSION: &str = "dnsliar";
+// A matchclass without qtypes_ filters the address queries only, like before the qtypes existed
+const DEFAULT_FILTERED_QTYPES: [RecordType; 2] = [RecordType::A, RecordType::AAAA];
+// RFC 2181 limits TTLs to 2^31 - 1
+const MAX_BLACKHOLE_TTL: u32 = 2147483647;
+const QUERY_LOG_MAX_LENGTH: u32 = 100000;
+const STORE_BATCH_SIZE: usize = 1000;
+pub const SCAN_PAGE_SIZE: usize = 1000;
+const DEFAULT_TCP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_TCP_TIMEOUT_SECS: u64 = 3600;
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_DRAIN_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_BLOCKLIST_REFRESH: Duration = Duration::from_secs(86400);
+const MIN_BLOCKLIST_REFRESH_SECS: u64 = 60;
+const DEFAULT_FORWARDER_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_RRL_WINDOW_SECS: u64 = 1;
+const DEFAULT_RRL_SLIP: u32 = 2;
+const MAX_UPSTREAM_TIMEOUT_MS: u64 = 60000;
+const MAX_UPSTREAM_ATTEMPTS: usize = 10;
+const DEFAULT_MATCH_CACHE_TTL_SECS: u64 = 30;
+const DEFAULT_MALFORMED_LOG_SAMPLE: u64 = 100;
+const DEFAULT_SELFTEST_NAME: &str = "a.root-servers.net.";
+const DEFAULT_POLICY_SERVICE_TIMEOUT_MS: u64 = 500;
+const DEFAULT_POLICY_SERVICE_CACHE_TTL_SECS: u64 = 10;
+
+// Whether the last command reached Redis, the ConnectionManager reconnects on its own without telling
+static REDIS_UP: AtomicBool = AtomicBool::new(true);
+
+// Logs the Redis connection going down and coming back, once per change
+fn track <T> (
+    result: redis::RedisResult<T>
+)
+-> redis::RedisResult<T> {
+    match &result {
+        Ok(_) => {
+            if !REDIS_UP.swap(true, Ordering::Relaxed) {
+                info!("{}: Connection to Redis recovered", CONFILE.daemon_id)
+            }
+        },
+        Err(error) if error.is_connection_dropped() || error.is_connection_refusal() || error.is_io_error() || error.is_timeout() => {
+            if REDIS_UP.swap(false, Ordering::Relaxed) {
+                warn!("{}: Connection to Redis failed, the manager will reconnect: {}", CONFILE.daemon_id, error)
+            }
+        },
+        Err(_) => ()
+    }
+    return result
+}
+
+fn build_connection_info ()
+-> DnsLrResult<ConnectionInfo> {
+    // Without a port Redis is reached on its default one, a bare IPv6 address has colons but no port
+    let (host, port) = match CONFILE.redis_address.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => (host, Some(port)),
+        _ => (CONFILE.redis_address.as_str(), None)
+    };
+    let port = match port.map(|port| port.parse::<u16>()) {
+        Some(Ok(port)) => port,
+        Some(Err(_)) => return Err(WrappedErrors::DNSlrError(ErrorKind::InvalidRedisAddress(CONFILE.redis_address.clone()))),
+        None => DEFAULT_REDIS_PORT
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+
+    let addr = match CONFILE.redis_tls {
+        true => ConnectionAddr::TcpTls {host, port, insecure: false},
+        false => ConnectionAddr::Tcp(host, port)
+    };
+
+    return Ok(ConnectionInfo {
+        addr,
+        redis: RedisConnectionInfo {
+            db: CONFILE.redis_db.unwrap_or(0),
+            username: CONFILE.redis_username.clone(),
+            password: CONFILE.redis_password.clone()
+        }
+    })
+}
+
+pub async fn build_manager ()
+-> DnsLrResult<ConnectionManager> {
+    let client = Client::open(build_connection_info()?)?;
+    info!("{}: Redis server probe successful", &CONFILE.daemon_id);
+
+    let max_attempts = CONFILE.redis_max_attempts.unwrap_or(DEFAULT_REDIS_MAX_ATTEMPTS).max(1);
+    let retry_delay = Duration::from_millis(CONFILE.redis_retry_delay_ms.unwrap_or(DEFAULT_REDIS_RETRY_DELAY_MS));
+
+    let mut attempt: u32 = 1;
+    let manager = loop {
+        match client.get_tokio_connection_manager().await {
+            Ok(manager) => break manager,
+            Err(error) if error.kind() == redis::ErrorKind::AuthenticationFailed => {
+                return Err(WrappedErrors::DNSlrError(ErrorKind::RedisAuthError))
+            },
+            Err(error) if attempt < max_attempts => {
+                let delay = retry_delay.saturating_mul(2u32.saturating_pow(attempt - 1));
+                warn!("{}: Connection attempt {} out of {} to Redis failed: {}, retrying in {}ms",
+                    &CONFILE.daemon_id, attempt, max_attempts, error, delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1
+            },
+            Err(error) if CONFILE.redis_tls && error.kind() == redis::ErrorKind::IoError => {
+                return Err(WrappedErrors::DNSlrError(ErrorKind::RedisTlsError(error)))
+            },
+            Err(error) => {
+                return Err(WrappedErrors::DNSlrError(ErrorKind::RedisConnectionError {attempts: max_attempts, source: error}))
+            }
+        }
+    };
+    info!("{}: Connection to Redis successful", &CONFILE.daemon_id);
+
+    return Ok(manager)
+}
+
+// Hands out the connections in turn, a pool of one behaves like the single shared connection
+#[derive(Clone)]
+pub struct RedisPool {
+    managers: Arc<Vec<ConnectionManager>>,
+    next: Arc<AtomicUsize>
+}
+impl RedisPool {
+    pub fn get (
+        &self
+    )
+    -> ConnectionManager {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.managers.len();
+        return self.managers[index].clone()
+    }
+}
+
+pub async fn build_pool ()
+-> DnsLrResult<RedisPool> {
+    let size = CONFILE.redis_pool_size.unwrap_or(1).clamp(1, MAX_REDIS_POOL_SIZE);
+
+    let mut managers = Vec::with_capacity(size);
+    for _ in 0..size {
+        managers.push(build_manager().await?)
+    }
+    if size > 1 {
+        info!("{}: Redis pool of {} connections built", &CONFILE.daemon_id, size)
+    }
+
+    return Ok(RedisPool {
+        managers: Arc::new(managers),
+        next: Arc::new(AtomicUsize::new(0))
+    })
+}
+
+// Every key and channel name goes through here so that key_prefix isolates daemons sharing a Redis
+pub fn key (
+    name: &str
+)
+-> String {
+    return match &CONFILE.key_prefix {
+        Some(key_prefix) => format!("{}{}", key_prefix, name),
+        None => name.to_string()
+    }
+}
+
+// The "{matchclass}:{domain}" key of a domain listed in a matchclass, the domain in lowercase with a trailing dot
+// With wildcard_matching, "{matchclass}:.{domain}" lists the domain and all its subdomains: "ads:.example.com."
+pub fn match_key (
+    matchclass: &str,
+    domain: &str
+)
+-> String {
+    return key(&format!("{}:{}", matchclass, domain))
+}
+
+pub async fn build_pubsub (
+    channel: &str
+)
+-> DnsLrResult<PubSub> {
+    let client = Client::open(build_connection_info()?)?;
+
+    let mut pubsub = client.get_tokio_connection().await?.into_pubsub();
+    pubsub.subscribe(channel).await?;
+
+    return Ok(pubsub)
+}
+
+// Each call takes the next connection of the pool
+#[async_trait::async_trait]
+impl Backend for RedisPool {
+    async fn fields (
+        &self,
+        kind: &str,
+        daemon_id: &str
+    )
+    -> DnsLrResult<Vec<String>> {
+        hkeys(&mut self.get(), kind, daemon_id).await
+    }
+
+    async fn entries (
+        &self,
+        kind: &str,
+        daemon_id: &str
+    )
+    -> DnsLrResult<HashMap<String, String>> {
+        hgetall(&mut self.get(), kind, daemon_id).await
+    }
+
+    async fn exists_batch (
+        &self,
+        keys: &[String]
+    )
+    -> DnsLrResult<Vec<bool>> {
+        exists_batch(&mut self.get(), keys).await
+    }
+}
+
+pub async fn build_config (
+    backend: &dyn Backend
+)
+-> DnsLrResult<Config> {
+    let mut config = Config {
+        daemon_id: CONFILE.daemon_id.clone(),
+        forwarders: vec![],
+        forward_zones: vec![],
+        parallel_forwarding: false,
+        forwarder_strategy: None,
+        forwarder_source_ip: None,
+        forwarder_probe: None,
+        selftest: None,
+        upstream_timeout: None,
+        upstream_attempts: None,
+        dnssec: false,
+        ecs: None,
+        cache_size: None,
+        positive_cache: true,
+        negative_cache: true,
+        cache_min_ttl: None,
+        cache_max_ttl: None,
+        min_ttl: None,
+        max_ttl: None,
+        binds : vec![],
+        enable_udp: true,
+        enable_tcp: true,
+        reuseport: false,
+        tcp_timeout: DEFAULT_TCP_TIMEOUT,
+        drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        tcp_keepalive: None,
+        metrics_bind: None,
+        health_bind: None,
+        stats_file: None,
+        stats_persist: None,
+        query_log: false,
+        debug_queries: false,
+        minimal_responses: false,
+        any_queries: AnyQueries::Pass,
+        unsupported_qtypes_hinfo: false,
+        chaos_version: DEFAULT_CHAOS_VERSION.to_string(),
+        chaos_hide_id: false,
+        rate_limit: None,
+        max_inflight: None,
+        malformed_log_sample: DEFAULT_MALFORMED_LOG_SAMPLE,
+        rrl: None,
+        allowed_networks: vec![],
+        bypass_networks: vec![],
+        redis_failure_mode: RedisFailureMode::FailClosed,
+        pubsub_reload: false,
+        static_records: false,
+        static_ttl: DEFAULT_STATIC_TTL,
+        blocklists: vec![],
+        blocklist_refresh: DEFAULT_BLOCKLIST_REFRESH,
+        is_filtering: false,
+        filter_mode: FilterMode::BlackholeIp,
+        svcb_filter_mode: None,
+        extended_error: None,
+        subdomain_matching: false,
+        wildcard_matching: false,
+        match_cache: None,
+        blackhole_ttl: DEFAULT_BLACKHOLE_TTL,
+        blackhole_ptr: DEFAULT_BLACKHOLE_PTR.to_string(),
+        soa_minimum_ttl: DEFAULT_SOA_MINIMUM_TTL,
+        soa_mname: DEFAULT_SOA_MNAME.to_string(),
+        soa_rname: DEFAULT_SOA_RNAME.to_string(),
+        soa_serial: 0,
+        matchclasses: None,
+        policy_service: None,
+        policies: HashMap::new(),
+        blackhole_ips: None
+    };
+
+    let mut errors: Vec<String> = vec![];
+
+    match build_filtering(backend, &mut config).await {
+        Ok(()) => (),
+        Err(WrappedErrors::DNSlrError(ErrorKind::ConfigValidation(filtering_errors))) => errors.extend(filtering_errors),
+        Err(error) => return Err(error)
+    }
+
+    let ser_forwarders = get(backend, "forwarders", &config.daemon_id).await?;
+    let forwarders_count = ser_forwarders.len() as u8;
+    if forwarders_count == 0 {
+        reject(&mut errors, &config.daemon_id, "No forwarder was received".to_string())
+    } else {
+        info!("{}: Received {} forwarders", config.daemon_id, forwarders_count);
+
+        let mut valid_forwarder_count: u8 = 0;
+        for forwarder in ser_forwarders {
+            config.forwarders.push(
+                match Forwarder::parse(&forwarder) {
+                    Some(some) => some,
+                    None => {
+                        warn!("{}: forwarder: {} is not valid", config.daemon_id, forwarder);
+                        continue
+                    }
+                }
+            );
+            valid_forwarder_count += 1
+        }
+        if valid_forwarder_count == 0 {
+            reject(&mut errors, &config.daemon_id, "No forwarder is valid".to_string())
+        } else if valid_forwarder_count == forwarders_count {
+            info!("{}: all {} forwarders are valid", config.daemon_id, valid_forwarder_count)
+        } else {
+            warn!("{}: {} out of {} forwarders are valid", config.daemon_id, valid_forwarder_count, forwarders_count)
+        }
+    }
+
+    // Each field is a zone, its value the forwarders of the zone separated by commas
+    for (zone, forwarders) in get_all(backend, "forward_zones", &config.daemon_id).await? {
+        let Ok(zone_name) = Name::from_ascii(&zone).map(|name| name.to_lowercase()) else {
+            warn!("{}: forward zone: {} is not a valid name", config.daemon_id, zone);
+            continue
+        };
+        let zone_forwarders: Vec<Forwarder> = forwarders.split(',')
+            .map(str::trim)
+            .filter_map(|forwarder| match Forwarder::parse(forwarder) {
+                Some(some) => Some(some),
+                None => {
+                    warn!("{}: forwarder: {} of forward zone: {} is not valid", config.daemon_id, forwarder, zone);
+                    None
+                }
+            })
+            .collect();
+        if zone_forwarders.is_empty() {
+            warn!("{}: forward zone: {} has no valid forwarder, its names go to the default forwarders", config.daemon_id, zone);
+            continue
+        }
+        info!("{}: Names under {} will be forwarded to {} forwarders", config.daemon_id, zone_name, zone_forwarders.len());
+        config.forward_zones.push((zone_name, zone_forwarders))
+    }
+    // Redis returns the fields in no particular order, a name is routed by the deepest zone it is under
+    config.forward_zones.sort_by(|(a, _), (b, _)| b.num_labels().cmp(&a.num_labels()).then_with(|| a.cmp(b)));
+
+    config.parallel_forwarding = get_bool(backend, "parallel_forwarding", &config.daemon_id).await?.unwrap_or(false);
+    if config.parallel_forwarding {
+        info!("{}: Queries will be sent to all forwarders at once", config.daemon_id)
+    }
+
+    let tmp_forwarder_strategy = get(backend, "forwarder_strategy", &config.daemon_id).await?;
+    if let Some(forwarder_strategy) = tmp_forwarder_strategy.first() {
+        match forwarder_strategy.as_str() {
+            "failover" => config.forwarder_strategy = Some(ForwarderStrategy::Failover),
+            "round_robin" => config.forwarder_strategy = Some(ForwarderStrategy::RoundRobin),
+            "random" => config.forwarder_strategy = Some(ForwarderStrategy::Random),
+            _ => warn!("{}: forwarder_strategy: {} is not valid, defaulting to the fastest forwarder", config.daemon_id, forwarder_strategy)
+        }
+        if let Some(forwarder_strategy) = config.forwarder_strategy {
+            info!("{}: Forwarder strategy is {:?}", config.daemon_id, forwarder_strategy)
+        }
+    }
+
+    // Binding it once tells a local address from one the host does not hold
+    if let Some(forwarder_source_ip) = get(backend, "forwarder_source_ip", &config.daemon_id).await?.first() {
+        match forwarder_source_ip.parse::<IpAddr>() {
+            Ok(source_ip) => match std::net::UdpSocket::bind((source_ip, 0)) {
+                Ok(_) => {
+                    info!("{}: Queries to the forwarders will leave from {}", config.daemon_id, source_ip);
+                    if !config.forwarders.iter().any(|forwarder| forwarder.address.is_ipv4() == source_ip.is_ipv4()) {
+                        warn!("{}: forwarder_source_ip: {} matches the family of no forwarder, it will not be used", config.daemon_id, source_ip)
+                    }
+                    config.forwarder_source_ip = Some(source_ip)
+                },
+                Err(error) => warn!("{}: forwarder_source_ip: {} cannot be bound: {}, queries to the forwarders will leave from any address", config.daemon_id, source_ip, error)
+            },
+            Err(_) => warn!("{}: forwarder_source_ip: {} is not a valid IP address, queries to the forwarders will leave from any address", config.daemon_id, forwarder_source_ip)
+        }
+    }
+
+    if let Some(secs) = get_number::<u64>(backend, "forwarder_probe_interval", &config.daemon_id).await? {
+        let threshold = get_number::<u32>(backend, "forwarder_failure_threshold", &config.daemon_id).await?.unwrap_or(DEFAULT_FORWARDER_FAILURE_THRESHOLD);
+        match (secs, threshold) {
+            (0, _) | (_, 0) => warn!("{}: forwarder_probe_interval: {} or forwarder_failure_threshold: {} is 0, forwarders will not be probed", config.daemon_id, secs, threshold),
+            _ => {
+                config.forwarder_probe = Some((Duration::from_secs(secs), threshold));
+                info!("{}: Forwarders will be probed every {}s and left out after {} failed probes", config.daemon_id, secs, threshold)
+            }
+        }
+    }
+
+    if get_bool(backend, "selftest", &config.daemon_id).await?.unwrap_or(false) {
+        let tmp_selftest_name = get(backend, "selftest_name", &config.daemon_id).await?;
+        let selftest_name = tmp_selftest_name.first().map_or(DEFAULT_SELFTEST_NAME, String::as_str);
+        let selftest_strict = get_bool(backend, "selftest_strict", &config.daemon_id).await?.unwrap_or(false);
+        match Name::from_ascii(selftest_name) {
+            Ok(name) => {
+                info!("{}: {} will be resolved at startup, a failure {}", config.daemon_id, name, if selftest_strict { "stops the server" } else { "is only logged" });
+                config.selftest = Some((name, selftest_strict))
+            },
+            Err(_) => reject(&mut errors, &config.daemon_id, format!("selftest_name: {} is not a valid name", selftest_name))
+        }
+    }
+
+    if let Some(upstream_timeout_ms) = get_number::<u64>(backend, "upstream_timeout_ms", &config.daemon_id).await? {
+        match upstream_timeout_ms > 0 && upstream_timeout_ms <= MAX_UPSTREAM_TIMEOUT_MS {
+            true => {
+                config.upstream_timeout = Some(Duration::from_millis(upstream_timeout_ms));
+                info!("{}: Forwarders are given {}ms to answer", config.daemon_id, upstream_timeout_ms)
+            },
+            false => warn!("{}: upstream_timeout_ms: {} is not between 1 and {}, defaulting to the resolver's", config.daemon_id, upstream_timeout_ms, MAX_UPSTREAM_TIMEOUT_MS)
+        }
+    }
+    if let Some(upstream_attempts) = get_number::<usize>(backend, "upstream_attempts", &config.daemon_id).await? {
+        match upstream_attempts > 0 && upstream_attempts <= MAX_UPSTREAM_ATTEMPTS {
+            true => {
+                config.upstream_attempts = Some(upstream_attempts);
+                info!("{}: Forwarders are tried {} times", config.daemon_id, upstream_attempts)
+            },
+            false => warn!("{}: upstream_attempts: {} is not between 1 and {}, defaulting to the resolver's", config.daemon_id, upstream_attempts, MAX_UPSTREAM_ATTEMPTS)
+        }
+    }
+
+    config.dnssec = get_bool(backend, "dnssec", &config.daemon_id).await?.unwrap_or(false);
+
+    let ecs = get_bool(backend, "ecs", &config.daemon_id).await?.unwrap_or(false);
+    if ecs && config.dnssec {
+        warn!("{}: ecs queries would skip DNSSEC validation, ecs is disabled", config.daemon_id)
+    } else if ecs && config.forwarders.iter().any(|forwarder| forwarder.protocol != ForwarderProtocol::Plain) {
+        // ecs queries are sent over plain UDP, they would reveal the names meant to go through an encrypted forwarder
+        warn!("{}: ecs queries cannot go through the encrypted forwarders, ecs is disabled", config.daemon_id)
+    } else if ecs {
+        let ecs_prefix_v4 = get_number::<u8>(backend, "ecs_prefix_v4", &config.daemon_id).await?.unwrap_or(DEFAULT_ECS_PREFIX_V4);
+        let ecs_prefix_v6 = get_number::<u8>(backend, "ecs_prefix_v6", &config.daemon_id).await?.unwrap_or(DEFAULT_ECS_PREFIX_V6);
+        if ecs_prefix_v4 > 32 || ecs_prefix_v6 > 128 {
+            warn!("{}: ecs_prefix_v4: {} or ecs_prefix_v6: {} is not valid, ecs is disabled", config.daemon_id, ecs_prefix_v4, ecs_prefix_v6)
+        } else {
+            config.ecs = Some((ecs_prefix_v4, ecs_prefix_v6));
+            info!("{}: Forwarded queries will carry the client subnet as /{} and /{}", config.daemon_id, ecs_prefix_v4, ecs_prefix_v6)
+        }
+    }
+
+    config.cache_size = get_number(backend, "cache_size", &config.daemon_id).await?;
+    config.positive_cache = get_bool(backend, "positive_cache", &config.daemon_id).await?.unwrap_or(true);
+    config.negative_cache = get_bool(backend, "negative_cache", &config.daemon_id).await?.unwrap_or(true);
+    config.cache_min_ttl = get_number(backend, "cache_min_ttl", &config.daemon_id).await?;
+    config.cache_max_ttl = get_number(backend, "cache_max_ttl", &config.daemon_id).await?;
+    if let (Some(cache_min_ttl), Some(cache_max_ttl)) = (config.cache_min_ttl, config.cache_max_ttl) {
+        if cache_min_ttl > cache_max_ttl {
+            warn!("{}: cache_min_ttl: {} is over cache_max_ttl: {}, both are ignored", config.daemon_id, cache_min_ttl, cache_max_ttl);
+            config.cache_min_ttl = None;
+            config.cache_max_ttl = None
+        }
+    }
+
+    config.min_ttl = get_number(backend, "min_ttl", &config.daemon_id).await?;
+    config.max_ttl = get_number(backend, "max_ttl", &config.daemon_id).await?;
+    match (config.min_ttl, config.max_ttl) {
+        (Some(min_ttl), Some(max_ttl)) if min_ttl > max_ttl => {
+            warn!("{}: min_ttl: {} is over max_ttl: {}, both are ignored", config.daemon_id, min_ttl, max_ttl);
+            config.min_ttl = None;
+            config.max_ttl = None
+        },
+        (None, None) => (),
+        (min_ttl, max_ttl) => info!("{}: Forwarded answers are given TTLs between {}s and {}s", config.daemon_id, min_ttl.unwrap_or(0), max_ttl.unwrap_or(MAX_BLACKHOLE_TTL))
+    }
+
+    config.binds = get(backend, "binds", &config.daemon_id).await?;
+    let bind_count = config.binds.len() as u32;
+    if bind_count == 0 {
+        reject(&mut errors, &config.daemon_id, "No bind received".to_string())
+    } else {
+        info!("{}: Received {} binds", config.daemon_id, bind_count)
+    }
+
+    config.enable_udp = get_bool(backend, "enable_udp", &config.daemon_id).await?.unwrap_or(true);
+    config.enable_tcp = get_bool(backend, "enable_tcp", &config.daemon_id).await?.unwrap_or(true);
+    if !config.enable_udp || !config.enable_tcp {
+        info!("{}: UDP is {}, TCP is {}", config.daemon_id,
+            if config.enable_udp {"enabled"} else {"disabled"},
+            if config.enable_tcp {"enabled"} else {"disabled"})
+    }
+
+    config.reuseport = get_bool(backend, "reuseport", &config.daemon_id).await?.unwrap_or(false);
+    if config.reuseport {
+        info!("{}: Binds will be shared with other processes through SO_REUSEPORT", config.daemon_id)
+    }
+
+    let tmp_tcp_timeout = get(backend, "tcp_timeout", &config.daemon_id).await?;
+    if let Some(tcp_timeout) = tmp_tcp_timeout.first() {
+        match tcp_timeout.parse::<u64>() {
+            Ok(secs) if secs > 0 && secs <= MAX_TCP_TIMEOUT_SECS => {
+                config.tcp_timeout = Duration::from_secs(secs);
+                info!("{}: TCP timeout set to {}s", config.daemon_id, secs)
+            },
+            _ => warn!("{}: tcp_timeout: {} is not valid, defaulting to {}s", config.daemon_id, tcp_timeout, DEFAULT_TCP_TIMEOUT.as_secs())
+        }
+    }
+
+    if let Some(secs) = get_number::<u64>(backend, "drain_timeout_secs", &config.daemon_id).await? {
+        match secs <= MAX_DRAIN_TIMEOUT_SECS {
+            true => {
+                config.drain_timeout = Duration::from_secs(secs);
+                info!("{}: Queries in flight at shutdown are given {}s to finish", config.daemon_id, secs)
+            },
+            false => warn!("{}: drain_timeout_secs: {} is over {}, defaulting to {}s", config.daemon_id, secs, MAX_DRAIN_TIMEOUT_SECS, DEFAULT_DRAIN_TIMEOUT.as_secs())
+        }
+    }
+
+    // Connections are closed after tcp_timeout whatever the clients were told, so the keepalive cannot be longer
+    match get_number::<u64>(backend, "tcp_keepalive", &config.daemon_id).await? {
+        None | Some(0) => (),
+        Some(secs) => {
+            let mut keepalive = Duration::from_secs(secs);
+            if keepalive > config.tcp_timeout {
+                warn!("{}: tcp_keepalive: {} is over tcp_timeout, lowering it to {}s", config.daemon_id, secs, config.tcp_timeout.as_secs());
+                keepalive = config.tcp_timeout
+            }
+            config.tcp_keepalive = Some(keepalive);
+            info!("{}: TCP clients asking for it are told to keep their connection for {}s", config.daemon_id, keepalive.as_secs())
+        }
+    }
+
+    let tmp_metrics_bind = get(backend, "metrics_bind", &config.daemon_id).await?;
+    if let Some(metrics_bind) = tmp_metrics_bind.first() {
+        match metrics_bind.parse::<SocketAddr>() {
+            Ok(ok) => config.metrics_bind = Some(ok),
+            Err(_) => warn!("{}: metrics_bind: {} is not valid", config.daemon_id, metrics_bind)
+        }
+    }
+
+    let tmp_health_bind = get(backend, "health_bind", &config.daemon_id).await?;
+    if let Some(health_bind) = tmp_health_bind.first() {
+        match health_bind.parse::<SocketAddr>() {
+            Ok(ok) => config.health_bind = Some(ok),
+            Err(_) => warn!("{}: health_bind: {} is not valid", config.daemon_id, health_bind)
+        }
+    }
+
+    let tmp_stats_file = get(backend, "stats_file", &config.daemon_id).await?;
+    if let Some(stats_file) = tmp_stats_file.first() {
+        info!("{}: SIGUSR2 will also write the counters to {}", config.daemon_id, stats_file);
+        config.stats_file = Some(stats_file.clone())
+    }
+
+    match get_number::<u64>(backend, "stats_persist_interval", &config.daemon_id).await? {
+        None | Some(0) => (),
+        Some(secs) => {
+            config.stats_persist = Some(Duration::from_secs(secs));
+            info!("{}: Counters will be flushed to stats_{} every {}s", config.daemon_id, config.daemon_id, secs)
+        }
+    }
+
+    config.query_log = get_bool(backend, "query_log", &config.daemon_id).await?.unwrap_or(false);
+    if config.query_log {
+        info!("{}: Filtered queries will be logged to query_log_{}", config.daemon_id, config.daemon_id)
+    }
+
+    config.debug_queries = get_bool(backend, "debug_queries", &config.daemon_id).await?.unwrap_or(false);
+    if config.debug_queries {
+        info!("{}: TXT queries under _dnsliar. will tell whether a name is filtered", config.daemon_id)
+    }
+
+    config.minimal_responses = get_bool(backend, "minimal_responses", &config.daemon_id).await?.unwrap_or(false);
+    if config.minimal_responses {
+        info!("{}: Responses will only carry the answer section", config.daemon_id)
+    }
+
+    let tmp_any_queries = get(backend, "any_queries", &config.daemon_id).await?;
+    if let Some(any_queries) = tmp_any_queries.first() {
+        match any_queries.as_str() {
+            "pass" => config.any_queries = AnyQueries::Pass,
+            "hinfo" => config.any_queries = AnyQueries::Hinfo,
+            "refused" => config.any_queries = AnyQueries::Refused,
+            _ => warn!("{}: any_queries: {} is not valid, defaulting to pass", config.daemon_id, any_queries)
+        }
+        if config.any_queries != AnyQueries::Pass {
+            info!("{}: ANY queries are answered with {:?} and never forwarded", config.daemon_id, config.any_queries)
+        }
+    }
+
+    config.unsupported_qtypes_hinfo = get_bool(backend, "unsupported_qtypes_hinfo", &config.daemon_id).await?.unwrap_or(false);
+    if config.unsupported_qtypes_hinfo {
+        info!("{}: Queries of a qtype other than {:?} are answered with HINFO and never forwarded", config.daemon_id, resolver_mod::RESOLVED_QTYPES)
+    }
+
+    let tmp_chaos_version = get(backend, "chaos_version", &config.daemon_id).await?;
+    if let Some(chaos_version) = tmp_chaos_version.first() {
+        config.chaos_version = chaos_version.clone()
+    }
+    config.chaos_hide_id = get_bool(backend, "chaos_hide_id", &config.daemon_id).await?.unwrap_or(false);
+    if config.chaos_hide_id {
+        info!("{}: CHAOS queries on hostname.bind and id.server will be refused", config.daemon_id)
+    }
+
+    let tmp_rate_limit = get(backend, "rate_limit", &config.daemon_id).await?;
+    let tmp_rate_limit_refill = get(backend, "rate_limit_refill", &config.daemon_id).await?;
+    if let (Some(rate_limit), Some(rate_limit_refill)) = (tmp_rate_limit.first(), tmp_rate_limit_refill.first()) {
+        match (rate_limit.parse::<u32>(), rate_limit_refill.parse::<u32>()) {
+            (Ok(capacity), Ok(refill_per_sec)) if capacity > 0 => {
+                config.rate_limit = Some((capacity, refill_per_sec));
+                info!("{}: Clients are limited to bursts of {} queries, refilled by {} per second", config.daemon_id, capacity, refill_per_sec)
+            },
+            _ => warn!("{}: rate_limit: {} or rate_limit_refill: {} is not valid, clients will not be rate limited", config.daemon_id, rate_limit, rate_limit_refill)
+        }
+    }
+
+    config.max_inflight = get_number::<usize>(backend, "max_inflight", &config.daemon_id).await?.filter(|max_inflight| *max_inflight > 0);
+    if let Some(max_inflight) = config.max_inflight {
+        info!("{}: At most {} queries are processed at once", config.daemon_id, max_inflight)
+    }
+
+    config.malformed_log_sample = get_number::<u64>(backend, "malformed_log_sample", &config.daemon_id).await?.unwrap_or(DEFAULT_MALFORMED_LOG_SAMPLE);
+    match config.malformed_log_sample {
+        0 => info!("{}: Dropped malformed queries are not logged", config.daemon_id),
+        sample => info!("{}: One dropped malformed query in {} is logged", config.daemon_id, sample)
+    }
+
+    if let Some(rrl_limit) = get_number::<u32>(backend, "rrl_limit", &config.daemon_id).await? {
+        let rrl_window = get_number::<u64>(backend, "rrl_window", &config.daemon_id).await?.unwrap_or(DEFAULT_RRL_WINDOW_SECS);
+        let rrl_slip = get_number::<u32>(backend, "rrl_slip", &config.daemon_id).await?.unwrap_or(DEFAULT_RRL_SLIP);
+        match (rrl_limit, rrl_window) {
+            (0, _) | (_, 0) => warn!("{}: rrl_limit: {} or rrl_window: {} is 0, responses will not be rate limited", config.daemon_id, rrl_limit, rrl_window),
+            _ => {
+                config.rrl = Some((rrl_limit, Duration::from_secs(rrl_window), rrl_slip));
+                info!("{}: UDP responses are limited to {} per {}s per client subnet and response kind", config.daemon_id, rrl_limit, rrl_window)
+            }
+        }
+    }
+
+    for allowed_network in get(backend, "allowed_networks", &config.daemon_id).await? {
+        match Network::parse(&allowed_network) {
+            Some(network) => config.allowed_networks.push(network),
+            None => warn!("{}: allowed_network: {} is not a valid CIDR", config.daemon_id, allowed_network)
+        }
+    }
+    if !config.allowed_networks.is_empty() {
+        info!("{}: Only clients from {} networks will be answered", config.daemon_id, config.allowed_networks.len())
+    }
+
+    for bypass_network in get(backend, "bypass_networks", &config.daemon_id).await? {
+        match Network::parse(&bypass_network) {
+            Some(network) => config.bypass_networks.push(network),
+            None => warn!("{}: bypass_network: {} is not a valid CIDR", config.daemon_id, bypass_network)
+        }
+    }
+    if !config.bypass_networks.is_empty() {
+        info!("{}: Clients from {} networks will not be filtered", config.daemon_id, config.bypass_networks.len())
+    }
+
+    let tmp_redis_failure_mode = get(backend, "redis_failure_mode", &config.daemon_id).await?;
+    if let Some(redis_failure_mode) = tmp_redis_failure_mode.first() {
+        match redis_failure_mode.as_str() {
+            "fail_open" => config.redis_failure_mode = RedisFailureMode::FailOpen,
+            "fail_closed" => config.redis_failure_mode = RedisFailureMode::FailClosed,
+            _ => warn!("{}: redis_failure_mode: {} is not valid, defaulting to fail_closed", config.daemon_id, redis_failure_mode)
+        }
+    }
+    match config.redis_failure_mode {
+        RedisFailureMode::FailOpen => info!("{}: Queries will be forwarded unfiltered while Redis cannot be reached", config.daemon_id),
+        RedisFailureMode::FailClosed => info!("{}: Queries will be answered with SERVFAIL while Redis cannot be reached", config.daemon_id)
+    }
+
+    config.pubsub_reload = get_bool(backend, "pubsub_reload", &config.daemon_id).await?.unwrap_or(false);
+
+    config.static_records = get_bool(backend, "static_records", &config.daemon_id).await?.unwrap_or(false);
+    if config.static_records {
+        if let Some(static_ttl) = get_number::<u32>(backend, "static_ttl", &config.daemon_id).await? {
+            match static_ttl <= MAX_BLACKHOLE_TTL {
+                true => config.static_ttl = static_ttl,
+                false => warn!("{}: static_ttl: {} is over {}, defaulting to {}", config.daemon_id, static_ttl, MAX_BLACKHOLE_TTL, DEFAULT_STATIC_TTL)
+            }
+        }
+        info!("{}: Static records will be answered with a TTL of {}s", config.daemon_id, config.static_ttl)
+    }
+
+    // Each blocklist is stored as "matchclass=url"
+    for blocklist in get(backend, "blocklists", &config.daemon_id).await? {
+        let Some((matchclass, url)) = blocklist.split_once('=') else {
+            warn!("{}: blocklist: {} is not valid", config.daemon_id, blocklist);
+            continue
+        };
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            warn!("{}: blocklist: {} is not an HTTP URL", config.daemon_id, url);
+            continue
+        }
+        config.blocklists.push((matchclass.to_string(), url.to_string()))
+    }
+    if !config.blocklists.is_empty() {
+        if let Some(secs) = get_number::<u64>(backend, "blocklist_refresh", &config.daemon_id).await? {
+            match secs >= MIN_BLOCKLIST_REFRESH_SECS {
+                true => config.blocklist_refresh = Duration::from_secs(secs),
+                false => warn!("{}: blocklist_refresh: {} is under {}s, defaulting to {}s", config.daemon_id, secs, MIN_BLOCKLIST_REFRESH_SECS, DEFAULT_BLOCKLIST_REFRESH.as_secs())
+            }
+        }
+        info!("{}: {} blocklists will be refreshed every {}s", config.daemon_id, config.blocklists.len(), config.blocklist_refresh.as_secs())
+    }
+
+    // These read or write Redis keys of their own, the file backend has nowhere to keep them
+    if CONFILE.is_file_backend() {
+        let redis_only = [
+            ("stats_persist_interval", config.stats_persist.take().is_some()),
+            ("query_log", std::mem::take(&mut config.query_log)),
+            ("pubsub_reload", std::mem::take(&mut config.pubsub_reload)),
+            ("static_records", std::mem::take(&mut config.static_records)),
+            ("blocklists", !std::mem::take(&mut config.blocklists).is_empty())
+        ];
+        for (setting, _) in redis_only.iter().filter(|(_, was_set)| *was_set) {
+            warn!("{}: {} needs the Redis backend, it is ignored", config.daemon_id, setting)
+        }
+    }
+
+    if !errors.is_empty() {
+        error!("{}: The config has {} problems", config.daemon_id, errors.len());
+        return Err(WrappedErrors::DNSlrError(ErrorKind::ConfigValidation(errors)))
+    }
+
+    return Ok(config)
+}
+
+fn reject (
+    errors: &mut Vec<String>,
+    daemon_id: &str,
+    message: String
+) {
+    error!("{}: {}", daemon_id, message);
+    errors.push(message)
+}
+
+pub async fn build_filtering (
+    backend: &dyn Backend,
+    config: &mut Config
+)
+-> DnsLrResult<()> {
+    config.is_filtering = false;
+    config.filter_mode = FilterMode::BlackholeIp;
+    config.extended_error = None;
+    config.matchclasses = None;
+    config.policy_service = None;
+    config.policies = HashMap::new();
+    config.blackhole_ips = None;
+
+    let tmp_filter_mode = get(backend, "filter_mode", &config.daemon_id).await?;
+    if let Some(filter_mode) = tmp_filter_mode.first() {
+        match filter_mode.as_str() {
+            "blackhole_ip" => config.filter_mode = FilterMode::BlackholeIp,
+            "nxdomain" => config.filter_mode = FilterMode::Nxdomain,
+            "refused" => config.filter_mode = FilterMode::Refused,
+            _ => warn!("{}: filter_mode: {} is not valid, defaulting to blackhole_ip", config.daemon_id, filter_mode)
+        }
+    }
+    info!("{}: Filter mode is {:?}", config.daemon_id, config.filter_mode);
+
+    config.svcb_filter_mode = None;
+    let tmp_svcb_filter_mode = get(backend, "svcb_filter_mode", &config.daemon_id).await?;
+    if let Some(svcb_filter_mode) = tmp_svcb_filter_mode.first() {
+        match svcb_filter_mode.as_str() {
+            "nodata" => config.svcb_filter_mode = Some(SvcbFilterMode::Nodata),
+            "nxdomain" => config.svcb_filter_mode = Some(SvcbFilterMode::Nxdomain),
+            "refused" => config.svcb_filter_mode = Some(SvcbFilterMode::Refused),
+            _ => warn!("{}: svcb_filter_mode: {} is not valid, SVCB and HTTPS queries follow filter_mode", config.daemon_id, svcb_filter_mode)
+        }
+    }
+    if let Some(svcb_filter_mode) = config.svcb_filter_mode {
+        info!("{}: SVCB and HTTPS filter mode is {:?}", config.daemon_id, svcb_filter_mode)
+    }
+
+    // Off by default, clients that do not know the option would still get a longer OPT record
+    let tmp_extended_error = get(backend, "extended_error", &config.daemon_id).await?;
+    if let Some(extended_error) = tmp_extended_error.first() {
+        let extended_error = match extended_error.as_str() {
+            "blocked" => Some(ExtendedError::Blocked),
+            "censored" => Some(ExtendedError::Censored),
+            "filtered" => Some(ExtendedError::Filtered),
+            _ => {
+                warn!("{}: extended_error: {} is not valid, filtered answers will not carry one", config.daemon_id, extended_error);
+                None
+            }
+        };
+        if let Some(extended_error) = extended_error {
+            let extended_error_text = get(backend, "extended_error_text", &config.daemon_id).await?.into_iter().next();
+            info!("{}: Filtered answers will carry the extended error {:?}", config.daemon_id, extended_error);
+            config.extended_error = Some((extended_error, extended_error_text))
+        }
+    }
+
+    config.subdomain_matching = get_bool(backend, "subdomain_matching", &config.daemon_id).await?.unwrap_or(false);
+    if config.subdomain_matching {
+        info!("{}: Subdomain matching is enabled", config.daemon_id)
+    }
+    // The operators store "{matchclass}:.example.com." to match a name and all its subdomains
+    config.wildcard_matching = get_bool(backend, "wildcard_matching", &config.daemon_id).await?.unwrap_or(false);
+    if config.wildcard_matching {
+        info!("{}: Wildcard matching is enabled", config.daemon_id)
+    }
+
+    // A stale decision lives at most match_cache_ttl, reloading the matchclasses flushes them all
+    config.match_cache = None;
+    if let Some(match_cache_size) = get_number::<usize>(backend, "match_cache_size", &config.daemon_id).await?.filter(|size| *size > 0) {
+        let match_cache_ttl = get_number::<u64>(backend, "match_cache_ttl", &config.daemon_id).await?.unwrap_or(DEFAULT_MATCH_CACHE_TTL_SECS);
+        match match_cache_ttl {
+            0 => warn!("{}: match_cache_ttl is 0, match decisions will not be cached", config.daemon_id),
+            _ => {
+                config.match_cache = Some((match_cache_size, Duration::from_secs(match_cache_ttl)));
+                info!("{}: Up to {} match decisions are cached for {}s", config.daemon_id, match_cache_size, match_cache_ttl)
+            }
+        }
+    }
+
+    if let Some(url) = get(backend, "policy_service_url", &config.daemon_id).await?.into_iter().next() {
+        let timeout_ms = get_number::<u64>(backend, "policy_service_timeout_ms", &config.daemon_id).await?.unwrap_or(DEFAULT_POLICY_SERVICE_TIMEOUT_MS);
+        let cache_ttl = get_number::<u64>(backend, "policy_service_cache_ttl", &config.daemon_id).await?.unwrap_or(DEFAULT_POLICY_SERVICE_CACHE_TTL_SECS);
+        let tmp_failure_mode = get(backend, "policy_service_failure_mode", &config.daemon_id).await?;
+        let fail_open = match tmp_failure_mode.first().map(String::as_str) {
+            Some("fail_open") => true,
+            Some("fail_closed") | None => false,
+            Some(failure_mode) => {
+                warn!("{}: policy_service_failure_mode: {} is not valid, defaulting to fail_closed", config.daemon_id, failure_mode);
+                false
+            }
+        };
+        match (url.starts_with("http://") || url.starts_with("https://"), timeout_ms > 0 && timeout_ms <= MAX_UPSTREAM_TIMEOUT_MS) {
+            (false, _) => warn!("{}: policy_service_url: {} is not an HTTP URL, the policy service is disabled", config.daemon_id, url),
+            (_, false) => warn!("{}: policy_service_timeout_ms: {} is not between 1 and {}, the policy service is disabled", config.daemon_id, timeout_ms, MAX_UPSTREAM_TIMEOUT_MS),
+            (true, true) => {
+                info!("{}: Queries no matchclass decides are sent to {}, decisions are cached for {}s", config.daemon_id, url, cache_ttl);
+                config.policy_service = Some(PolicyService {
+                    url,
+                    timeout: Duration::from_millis(timeout_ms),
+                    cache_ttl: Duration::from_secs(cache_ttl),
+                    fail_open
+                })
+            }
+        }
+    }
+
+    config.blackhole_ttl = DEFAULT_BLACKHOLE_TTL;
+    let tmp_blackhole_ttl = get(backend, "blackhole_ttl", &config.daemon_id).await?;
+    if let Some(blackhole_ttl) = tmp_blackhole_ttl.first() {
+        match blackhole_ttl.parse::<u32>() {
+            Ok(ttl) if ttl <= MAX_BLACKHOLE_TTL => {
+                config.blackhole_ttl = ttl;
+                info!("{}: Blackhole TTL set to {}s", config.daemon_id, ttl)
+            },
+            _ => warn!("{}: blackhole_ttl: {} is not valid, defaulting to {}s", config.daemon_id, blackhole_ttl, DEFAULT_BLACKHOLE_TTL)
+        }
+    }
+
+    // NXDOMAIN lies carry an SOA so that clients know how long to cache them
+    config.soa_minimum_ttl = DEFAULT_SOA_MINIMUM_TTL;
+    config.soa_mname = DEFAULT_SOA_MNAME.to_string();
+    config.soa_rname = DEFAULT_SOA_RNAME.to_string();
+    // Serial arithmetic wraps, the low 32 bits of the time keep advancing
+    config.soa_serial = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as u32);
+    // The NXDOMAIN list and svcb_filter_mode lie with NXDOMAIN whatever filter_mode says
+    let nxdomain_list = get_bool(backend, "nxdomain_list", &config.daemon_id).await?.unwrap_or(false);
+    if config.filter_mode == FilterMode::Nxdomain || config.svcb_filter_mode == Some(SvcbFilterMode::Nxdomain) || nxdomain_list {
+        if let Some(soa_minimum_ttl) = get_number::<u32>(backend, "soa_minimum_ttl", &config.daemon_id).await? {
+            match soa_minimum_ttl <= MAX_BLACKHOLE_TTL {
+                true => config.soa_minimum_ttl = soa_minimum_ttl,
+                false => warn!("{}: soa_minimum_ttl: {} is over {}, defaulting to {}s", config.daemon_id, soa_minimum_ttl, MAX_BLACKHOLE_TTL, DEFAULT_SOA_MINIMUM_TTL)
+            }
+        }
+        for (kind, soa_name) in [("soa_mname", &mut config.soa_mname), ("soa_rname", &mut config.soa_rname)] {
+            let tmp_soa_name = get(backend, kind, &config.daemon_id).await?;
+            if let Some(name) = tmp_soa_name.first() {
+                match Name::from_ascii(name) {
+                    Ok(_) => *soa_name = name.clone(),
+                    Err(_) => warn!("{}: {}: {} is not a valid name, defaulting to {}", config.daemon_id, kind, name, soa_name)
+                }
+            }
+        }
+        info!("{}: NXDOMAIN lies are cached for {}s", config.daemon_id, config.soa_minimum_ttl)
+    }
+
+    // Reverse lookups of the blackhole IPs are answered with this name instead of being forwarded
+    config.blackhole_ptr = DEFAULT_BLACKHOLE_PTR.to_string();
+    if config.filter_mode == FilterMode::BlackholeIp {
+        let tmp_blackhole_ptr = get(backend, "blackhole_ptr", &config.daemon_id).await?;
+        if let Some(name) = tmp_blackhole_ptr.first() {
+            match Name::from_ascii(name) {
+                Ok(_) => config.blackhole_ptr = name.clone(),
+                Err(_) => warn!("{}: blackhole_ptr: {} is not a valid name, defaulting to {}", config.daemon_id, name, DEFAULT_BLACKHOLE_PTR)
+            }
+        }
+    }
+
+    if config.filter_mode == FilterMode::BlackholeIp {
+        // Filtered queries of a family left out of blackhole_families get an empty NOERROR instead
+        let mut blackhole_v4 = false;
+        let mut blackhole_v6 = false;
+        let tmp_blackhole_families = get(backend, "blackhole_families", &config.daemon_id).await?;
+        for family in tmp_blackhole_families.iter() {
+            match family.as_str() {
+                "v4" => blackhole_v4 = true,
+                "v6" => blackhole_v6 = true,
+                _ => warn!("{}: blackhole_families: {} is not valid, expecting v4 or v6", config.daemon_id, family)
+            }
+        }
+        if !blackhole_v4 && !blackhole_v6 {
+            (blackhole_v4, blackhole_v6) = (true, true)
+        }
+
+        let tmp_blackhole_ips = get(backend, "blackhole_ips", &config.daemon_id).await?;
+        if tmp_blackhole_ips.is_empty() {
+            warn!("{}: No blackhole_ips received (must have a v4 and v6 unless blackhole_families leaves one out)", config.daemon_id);
+            warn!("{}: The matchclasses will not filter any request and so will not lie", config.daemon_id);
+            return load_matchclasses(backend, config, false, nxdomain_list).await
+        }
+
+        // Entries are classified by family, several addresses of a family are handed out in turn
+        let mut errors: Vec<String> = vec![];
+        let mut tmp_blackhole_ipv4s: Vec<Ipv4Addr> = vec![];
+        let mut tmp_blackhole_ipv6s: Vec<Ipv6Addr> = vec![];
+        for blackhole_ip in tmp_blackhole_ips.iter() {
+            match blackhole_ip.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ipv4)) => tmp_blackhole_ipv4s.push(ipv4),
+                Ok(IpAddr::V6(ipv6)) => tmp_blackhole_ipv6s.push(ipv6),
+                Err(_) => reject(&mut errors, &config.daemon_id, format!("blackhole_ips: {} is not a valid IP", blackhole_ip))
+            }
+        }
+        // Redis returns the entries in no particular order, sorting keeps the rotation stable across reloads
+        tmp_blackhole_ipv4s.sort_unstable();
+        tmp_blackhole_ipv4s.dedup();
+        tmp_blackhole_ipv6s.sort_unstable();
+        tmp_blackhole_ipv6s.dedup();
+        if blackhole_v4 && tmp_blackhole_ipv4s.is_empty() {
+            reject(&mut errors, &config.daemon_id, "blackhole_ips: no v4 address was received".to_string())
+        }
+        if blackhole_v6 && tmp_blackhole_ipv6s.is_empty() {
+            reject(&mut errors, &config.daemon_id, "blackhole_ips: no v6 address was received".to_string())
+        }
+        if !errors.is_empty() {
+            return Err(WrappedErrors::DNSlrError(ErrorKind::ConfigValidation(errors)))
+        }
+        if !blackhole_v4 {
+            tmp_blackhole_ipv4s.clear()
+        }
+        if !blackhole_v6 {
+            tmp_blackhole_ipv6s.clear()
+        }
+        if tmp_blackhole_ipv4s.len() > 1 || tmp_blackhole_ipv6s.len() > 1 {
+            info!("{}: Lies rotate over {} v4 and {} v6 blackhole IPs", config.daemon_id, tmp_blackhole_ipv4s.len(), tmp_blackhole_ipv6s.len())
+        }
+        config.blackhole_ips = Some((tmp_blackhole_ipv4s, tmp_blackhole_ipv6s));
+        info!("{}: Blackhole_ips received are valid", config.daemon_id);
+        if !blackhole_v4 {
+            info!("{}: Filtered A queries are answered without records", config.daemon_id)
+        }
+        if !blackhole_v6 {
+            info!("{}: Filtered AAAA queries are answered without records", config.daemon_id)
+        }
+    }
+
+    return load_matchclasses(backend, config, true, nxdomain_list).await
+}
+
+// The matchclasses of the binds with and without a policy, then the NXDOMAIN list that goes with each of them
+// Without blackhole IPs to lie with, only the NXDOMAIN list and the policy service are left to filter
+async fn load_matchclasses (
+    backend: &dyn Backend,
+    config: &mut Config,
+    has_blackhole_ips: bool,
+    nxdomain_list: bool
+)
+-> DnsLrResult<()> {
+    let policies: HashSet<String> = config.binds.iter()
+        .filter_map(|bind| socket_mod::split_policy(bind).1)
+        .map(str::to_string)
+        .collect();
+    for policy in policies {
+        let matchclasses = match has_blackhole_ips {
+            true => get_matchclasses(backend, &format!("policy_{}_", policy), &config.daemon_id).await?,
+            false => None
+        };
+        if matchclasses.is_none() && has_blackhole_ips {
+            warn!("{}: No matchclass received for policy: {}, its binds will not filter any request", config.daemon_id, policy)
+        }
+        config.policies.insert(policy, matchclasses.unwrap_or_default());
+    }
+
+    if has_blackhole_ips {
+        config.matchclasses = get_matchclasses(backend, "", &config.daemon_id).await?;
+    }
+
+    // The NXDOMAIN list goes with every set of matchclasses, the binds of a policy included
+    if nxdomain_list {
+        let match_mode = get_match_mode(backend, matching::NXDOMAIN_MATCHCLASS, &config.daemon_id).await?;
+        let nxdomain_matchclass = matching::nxdomain_matchclass(match_mode);
+        for matchclasses in config.policies.values_mut() {
+            matchclasses.push(nxdomain_matchclass.clone())
+        }
+        config.matchclasses.get_or_insert_with(Vec::new).push(nxdomain_matchclass);
+        info!("{}: Names listed in the {} matchclass are answered with NXDOMAIN", config.daemon_id, matching::NXDOMAIN_MATCHCLASS)
+    }
+
+    if config.matchclasses.is_none() && has_blackhole_ips {
+        warn!("{}: No matchclass received", config.daemon_id);
+        match config.policies.is_empty() {
+            true => warn!("{}: The server will not filter any request and so will not lie", config.daemon_id),
+            false => warn!("{}: The binds without a policy will not filter any request", config.daemon_id)
+        }
+    }
+
+    // The redirects of the policy service carry their own addresses, it decides with or without blackhole IPs
+    if !has_blackhole_ips && config.policy_service.is_some() {
+        info!("{}: The policy service still decides the queries without blackhole_ips, its blocks are answered without records", config.daemon_id)
+    }
+    config.is_filtering = config.matchclasses.is_some() || config.policies.values().any(|matchclasses| !matchclasses.is_empty())
+        || config.policy_service.is_some();
+
+    return Ok(())
+}
+
+// Reads the "{prefix}matchclasses" and "{prefix}allow_matchclasses" lists, None when no block matchclass is listed
+async fn get_matchclasses (
+    backend: &dyn Backend,
+    prefix: &str,
+    daemon_id: &String
+)
+-> DnsLrResult<Option<Vec<Matchclass>>> {
+    let tmp_matchclasses = get(backend, &format!("{}matchclasses", prefix), daemon_id).await?;
+    let matchclasses_count = tmp_matchclasses.len();
+    if matchclasses_count == 0 {
+        return Ok(None)
+    }
+
+    let mut matchclasses: Vec<Matchclass> = vec![];
+    for name in tmp_matchclasses {
+        let qtypes = get_qtypes(backend, &name, daemon_id).await?;
+        let match_mode = get_match_mode(backend, &name, daemon_id).await?;
+        let mut matchclass = Matchclass {
+            name,
+            is_allow: false,
+            is_nxdomain: false,
+            is_redirect: false,
+            blackhole_ipv4s: vec![],
+            blackhole_ipv6s: vec![],
+            qtypes,
+            match_mode
+        };
+
+        // The value of each IP is its weight, a value that is not a number weighs 1
+        let tmp_blackhole_ips = get_all(backend, &format!("blackhole_ips_{}", matchclass.name), daemon_id).await?;
+        let mut is_weighted = false;
+        for (blackhole_ip, weight) in tmp_blackhole_ips {
+            let weight = match weight.parse::<u32>() {
+                Ok(weight) => {
+                    is_weighted = true;
+                    weight
+                },
+                Err(_) => 1
+            };
+            match blackhole_ip.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ipv4)) => matchclass.blackhole_ipv4s.push((ipv4, weight)),
+                Ok(IpAddr::V6(ipv6)) => matchclass.blackhole_ipv6s.push((ipv6, weight)),
+                Err(_) => warn!("{}: blackhole_ip: {} of matchclass: {} is not valid", daemon_id, blackhole_ip, matchclass.name)
+            }
+        }
+        // Redis returns the entries in no particular order, sorting keeps the picks stable across reloads
+        matchclass.blackhole_ipv4s.sort_unstable();
+        matchclass.blackhole_ipv6s.sort_unstable();
+        if is_weighted {
+            info!("{}: Matchclass: {} picks its blackhole IPs by weight", daemon_id, matchclass.name)
+        }
+        matchclasses.push(matchclass)
+    }
+
+    let tmp_allow_matchclasses = get(backend, &format!("{}allow_matchclasses", prefix), daemon_id).await?;
+    let allow_matchclasses_count = tmp_allow_matchclasses.len();
+    for name in tmp_allow_matchclasses {
+        let qtypes = get_qtypes(backend, &name, daemon_id).await?;
+        let match_mode = get_match_mode(backend, &name, daemon_id).await?;
+        matchclasses.push(Matchclass {
+            name,
+            is_allow: true,
+            is_nxdomain: false,
+            is_redirect: false,
+            blackhole_ipv4s: vec![],
+            blackhole_ipv6s: vec![],
+            qtypes,
+            match_mode
+        })
+    }
+
+    info!("{}: Received {} {}matchclasses and {} {}allow matchclasses", daemon_id, matchclasses_count, prefix, allow_matchclasses_count, prefix);
+    return Ok(Some(matchclasses))
+}
+
+// Reads the qtypes a matchclass applies to, "*" or "ALL" applies it to every qtype
+async fn get_qtypes (
+    backend: &dyn Backend,
+    matchclass: &str,
+    daemon_id: &String
+)
+-> DnsLrResult<QtypeFilter> {
+    let tmp_qtypes = get(backend, &format!("qtypes_{}", matchclass), daemon_id).await?;
+
+    let mut qtypes: Vec<RecordType> = vec![];
+    for qtype in tmp_qtypes {
+        if qtype == "*" || qtype.eq_ignore_ascii_case("ALL") {
+            return Ok(QtypeFilter::All)
+        }
+        match RecordType::from_str(&qtype.to_uppercase()) {
+            Ok(RecordType::Unknown(_)) | Err(_) => warn!("{}: qtype: {} of matchclass: {} is not valid", daemon_id, qtype, matchclass),
+            Ok(record_type) => qtypes.push(record_type)
+        }
+    }
+
+    if qtypes.is_empty() {
+        qtypes = DEFAULT_FILTERED_QTYPES.to_vec()
+    }
+    return Ok(QtypeFilter::Only(qtypes))
+}
+
+// Reads match_mode_{matchclass}, contains also reads the tokens listed in contains_{matchclass}
+async fn get_match_mode (
+    backend: &dyn Backend,
+    matchclass: &str,
+    daemon_id: &String
+)
+-> DnsLrResult<MatchMode> {
+    let tmp_match_mode = get(backend, &format!("match_mode_{}", matchclass), daemon_id).await?;
+    let Some(match_mode) = tmp_match_mode.first() else {
+        return Ok(MatchMode::Exact)
+    };
+
+    return match match_mode.as_str() {
+        "exact" => Ok(MatchMode::Exact),
+        "suffix" => {
+            info!("{}: Matchclass: {} also matches the subdomains of its domains", daemon_id, matchclass);
+            Ok(MatchMode::Suffix)
+        },
+        "contains" => {
+            let tokens: Vec<String> = get(backend, &format!("contains_{}", matchclass), daemon_id).await?
+                .into_iter()
+                .map(|token| token.to_lowercase())
+                .collect();
+            if tokens.is_empty() {
+                warn!("{}: No token received for the contains matchclass: {}, it will not match anything", daemon_id, matchclass)
+            } else {
+                info!("{}: Matchclass: {} matches names containing any of its {} tokens", daemon_id, matchclass, tokens.len())
+            }
+            Ok(MatchMode::Contains(tokens))
+        },
+        _ => {
+            warn!("{}: match_mode: {} of matchclass: {} is not valid, defaulting to exact", daemon_id, match_mode, matchclass);
+            Ok(MatchMode::Exact)
+        }
+    }
+}
+
+// Pipelines one exists check per candidate, a missing or nil reply counts as not found
+async fn exists_batch (
+    manager: &mut ConnectionManager,
+    candidates: &[String]
+)
+-> DnsLrResult<Vec<bool>> {
+    if candidates.is_empty() {
+        return Ok(vec![])
+    }
+
+    let mut pipe = redis::pipe();
+    for fullmatch in candidates.iter() {
+        pipe.cmd("EXISTS").arg(fullmatch);
+    }
+    let replies: Vec<redis::Value> = track(pipe.query_async(manager).await)?;
+
+    let mut found: Vec<bool> = replies.iter()
+        .map(|reply| matches!(reply, redis::Value::Int(count) if *count > 0))
+        .collect();
+    found.resize(candidates.len(), false);
+    return Ok(found)
+}
+
+// SCAN MATCH takes a glob, a matchclass or key_prefix holding one of its special characters is matched literally
+fn escape_glob (
+    pattern: &str
+)
+-> String {
+    let mut escaped = String::with_capacity(pattern.len());
+    for character in pattern.chars() {
+        if matches!(character, '*' | '?' | '[' | ']' | '\\' | '^') {
+            escaped.push('\\')
+        }
+        escaped.push(character)
+    }
+    return escaped
+}
+
+// One page of the domains listed in a matchclass, start with cursor 0 and stop once the returned cursor is 0 again
+// SCAN never blocks Redis for long, but a page may come back empty or hold a domain already seen on a previous page
+pub async fn scan_matchclass (
+    manager: &mut ConnectionManager,
+    matchclass: &str,
+    cursor: u64,
+    page_size: usize
+)
+-> DnsLrResult<(u64, Vec<String>)> {
+    let prefix = match_key(matchclass, "");
+    let (next_cursor, keys): (u64, Vec<String>) = track(redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(format!("{}*", escape_glob(&prefix)))
+        .arg("COUNT")
+        .arg(page_size)
+        .query_async(manager)
+        .await)?;
+
+    let domains = keys.into_iter()
+        .filter_map(|key| key.strip_prefix(&prefix).map(str::to_string))
+        .collect();
+    return Ok((next_cursor, domains))
+}
+
+// Writes the "{matchclass}:{domain}" keys checked by exists_batch, in batches to limit round trips
+pub async fn store_matches (
+    manager: &mut ConnectionManager,
+    matchclass: &str,
+    domains: &[String]
+)
+-> DnsLrResult<()> {
+    for chunk in domains.chunks(STORE_BATCH_SIZE) {
+        let mut pipe = redis::pipe();
+        for domain in chunk {
+            pipe.cmd("SET").arg(match_key(matchclass, domain)).arg(1).ignore();
+        }
+        pipe.query_async::<_, ()>(manager).await?;
+    }
+    return Ok(())
+}
+
+// The blocklist set remembers which keys a remote list wrote, so entries dropped upstream are removed
+pub async fn replace_blocklist (
+    manager: &mut ConnectionManager,
+    matchclass: &str,
+    daemon_id: &String,
+    domains: &[String]
+)
+-> DnsLrResult<()> {
+    let set = key(&format!("blocklist_{}_{}", matchclass, daemon_id));
+    let previous: Vec<String> = track(redis::cmd("SMEMBERS").arg(&set).query_async(manager).await)?;
+    let current: HashSet<&String> = domains.iter().collect();
+    let stale: Vec<String> = previous.into_iter().filter(|domain| !current.contains(domain)).collect();
+
+    for chunk in stale.chunks(STORE_BATCH_SIZE) {
+        let mut pipe = redis::pipe();
+        for domain in chunk {
+            pipe.cmd("DEL").arg(match_key(matchclass, domain)).ignore();
+            pipe.cmd("SREM").arg(&set).arg(domain).ignore();
+        }
+        track(pipe.query_async::<_, ()>(manager).await)?;
+    }
+
+    store_matches(manager, matchclass, domains).await?;
+    for chunk in domains.chunks(STORE_BATCH_SIZE) {
+        track(redis::cmd("SADD").arg(&set).arg(chunk).query_async::<_, ()>(manager).await)?;
+    }
+
+    info!("{}: Blocklist for {} has {} domains, {} were removed", daemon_id, matchclass, domains.len(), stale.len());
+    return Ok(())
+}
+
+// Returns the field of the queried qtype and the CNAME field of a static record
+pub async fn get_static (
+    manager: &mut ConnectionManager,
+    name: &str,
+    qtype: RecordType
+)
+-> DnsLrResult<(Option<String>, Option<String>)> {
+    let (value, cname): (Option<String>, Option<String>) = track(redis::cmd("HMGET")
+        .arg(key(&format!("static:{}", name)))
+        .arg(qtype.to_string())
+        .arg(RecordType::CNAME.to_string())
+        .query_async(manager)
+        .await)?;
+    return Ok((value, cname))
+}
+
+pub async fn log_query (
+    manager: &mut ConnectionManager,
+    daemon_id: &String,
+    client: IpAddr,
+    name: String,
+    matchclass: String
+)
+-> DnsLrResult<()> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+
+    track(manager.req_packed_command(
+        redis::Cmd::new()
+            .arg("XADD")
+            .arg(key(&format!("query_log_{}", daemon_id)))
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(QUERY_LOG_MAX_LENGTH)
+            .arg("*")
+            .arg("timestamp").arg(timestamp)
+            .arg("client").arg(client.to_string())
+            .arg("name").arg(name)
+            .arg("matchclass").arg(matchclass))
+            .await)?;
+
+    return Ok(())
+}
+
+// Adds the persisted counters to the ones counted so far, unknown or invalid fields are skipped
+pub async fn restore_stats (
+    manager: &mut ConnectionManager,
+    stats: &Stats,
+    daemon_id: &String
+)
+-> DnsLrResult<()> {
+    let ser_answer = track(manager.req_packed_command(
+        redis::Cmd::new()
+            .arg("HGETALL")
+            .arg(key(&format!("stats_{}", daemon_id))))
+            .await)?;
+
+    let persisted: HashMap<String, String> = redis::FromRedisValue::from_redis_value(&ser_answer)?;
+    for (name, counter) in stats.counters() {
+        if let Some(Ok(value)) = persisted.get(name).map(|value| value.parse::<u64>()) {
+            counter.fetch_add(value, Ordering::Relaxed);
+        }
+    }
+
+    return Ok(())
+}
+
+pub async fn persist_stats (
+    manager: &mut ConnectionManager,
+    stats: &Stats,
+    daemon_id: &String
+)
+-> DnsLrResult<()> {
+    let mut cmd = redis::Cmd::new();
+    cmd.arg("HSET").arg(key(&format!("stats_{}", daemon_id)));
+    for (name, counter) in stats.counters() {
+        cmd.arg(name).arg(counter.load(Ordering::Relaxed));
+    }
+    track(manager.req_packed_command(&cmd).await)?;
+
+    return Ok(())
+}
+
+pub async fn get (
+    backend: &dyn Backend,
+    kind: &str,
+    daemon_id: &str
+)
+-> DnsLrResult<Vec<String>> {
+    return backend.fields(kind, daemon_id).await
+}
+
+// The fields of the hash with their values, for the settings that carry a value per entry
+pub async fn get_all (
+    backend: &dyn Backend,
+    kind: &str,
+    daemon_id: &str
+)
+-> DnsLrResult<HashMap<String, String>> {
+    return backend.entries(kind, daemon_id).await
+}
+
+pub async fn hkeys (
+    manager: &mut ConnectionManager,
+    kind: &str,
+    daemon_id: &str
+)
+-> DnsLrResult<Vec<String>> {
+    let ser_answer = track(manager.req_packed_command(
+        redis::Cmd::new()
+            .arg("HKEYS")
+            .arg(key(&format!("{}_{}", kind, daemon_id))))
+            .await)?;
+
+    let deser_answer = redis::FromRedisValue::from_redis_value(&ser_answer)?;
+    return Ok(deser_answer)
+}
+
+async fn hgetall (
+    manager: &mut ConnectionManager,
+    kind: &str,
+    daemon_id: &str
+)
+-> DnsLrResult<HashMap<String, String>> {
+    let ser_answer = track(manager.req_packed_command(
+        redis::Cmd::new()
+            .arg("HGETALL")
+            .arg(key(&format!("{}_{}", kind, daemon_id))))
+            .await)?;
+
+    let deser_answer = redis::FromRedisValue::from_redis_value(&ser_answer)?;
+    return Ok(deser_answer)
+}
+
+pub async fn get_bool (
+    backend: &dyn Backend,
+    kind: &str,
+    daemon_id: &String
+)
+-> DnsLrResult<Option<bool>> {
+    let values = get(backend, kind, daemon_id).await?;
+
+    return match values.first().map(String::as_str) {
+        None => Ok(None),
+        Some("true") => Ok(Some(true)),
+        Some("false") => Ok(Some(false)),
+        Some(value) => {
+            warn!("{}: {}: {} is not valid, expecting true or false", daemon_id, kind, value);
+            Ok(None)
+        }
+    }
+}
+
+pub async fn get_number <T: FromStr> (
+    backend: &dyn Backend,
+    kind: &str,
+    daemon_id: &String
+)
+-> DnsLrResult<Option<T>> {
+    let values = get(backend, kind, daemon_id).await?;
+    let Some(value) = values.first() else {
+        return Ok(None)
+    };
+
+    return match value.parse::<T>() {
+        Ok(number) => Ok(Some(number)),
+        Err(_) => {
+            warn!("{}: {}: {} is not a valid number", daemon_id, kind, value);
+            Ok(None)
+        }
+    }
+}
\ No newline at end of file
@@ -1,51 +1,422 @@
-use crate::enums_structs::{Config, WrappedErrors, DnsLrResult, ErrorKind};
+use crate::enums_structs::{Config, WrappedErrors, DnsLrResult, ErrorKind, ExtendedError, Forwarder, ForwarderProtocol, ForwarderStrategy};
+use crate::CONFILE;
 
-use tracing::info;
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::{info, debug, warn};
+use futures_util::future::{self, BoxFuture, FutureExt, Ready, TryFutureExt};
+use rand::Rng;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, atomic::{AtomicUsize, Ordering}},
+    time::Duration
+};
 use trust_dns_client::{
-    op::{Header, ResponseCode},
-    rr::RecordType,
+    op::{Edns, Header, Message, Query, ResponseCode},
+    rr::{Name, RecordType},
+};
+use trust_dns_proto::{
+    rr::{Record, rdata::opt::EdnsOption},
+    xfer::{DnsHandle, DnsRequest}
 };
-use trust_dns_proto::rr::Record;
 use trust_dns_resolver::{
-    config::{ResolverConfig, ResolverOpts, NameServerConfig, Protocol},
+    config::{ResolverConfig, ResolverOpts, NameServerConfig, Protocol, ServerOrderingStrategy},
     TokioAsyncResolver,
     AsyncResolver,
-    name_server::{GenericConnection, GenericConnectionProvider, TokioRuntime},
+    name_server::{ConnectionProvider, GenericConnection, GenericConnectionProvider, NameServerPool, RuntimeProvider, TokioHandle, TokioRuntime},
     IntoName,
     error::{ResolveErrorKind, ResolveError},
     lookup::Lookup
 };
 use trust_dns_server::server::Request;
 
+pub const MIN_EDNS_PAYLOAD_SIZE: u16 = 512;
+pub const MAX_EDNS_PAYLOAD_SIZE: u16 = 4096;
+const DEFAULT_EDNS_PAYLOAD_SIZE: u16 = 1232;
+// The trust-dns UDP client reads the answers of the forwarders into a buffer of this size
+const MAX_UPSTREAM_PAYLOAD_SIZE: u16 = 2048;
+// The qtypes get_answers looks up, the others are answered NOTIMP unless unsupported_qtypes_hinfo is on
+pub const RESOLVED_QTYPES: [RecordType; 6] = [RecordType::A, RecordType::AAAA, RecordType::TXT, RecordType::SRV, RecordType::MX, RecordType::PTR];
+
+// The UDP payload size advertised to clients and to the forwarders, read_confile checks its range
+pub fn edns_payload_size ()
+-> u16 {
+    return CONFILE.edns_payload_size.unwrap_or(DEFAULT_EDNS_PAYLOAD_SIZE)
+}
+
+// The UDP payload size advertised to the forwarders by the resolver, what its UDP client can read at most
+fn upstream_payload_size ()
+-> u16 {
+    return edns_payload_size().min(MAX_UPSTREAM_PAYLOAD_SIZE)
+}
+
+// The resolver every forwarded query goes through
+pub type Resolver = AsyncResolver<RotatingConnection, RotatingConnectionProvider>;
+// The forwarders in one order, tried by trust-dns like the name servers of a resolver
+type ForwarderNameServers = NameServerPool<PayloadConnection, PayloadConnectionProvider>;
+
+// trust-dns sets a fixed payload size of 1232 in the OPT record of its queries, the connections put ours instead
+#[derive(Clone)]
+pub struct PayloadConnectionProvider(GenericConnectionProvider<TokioRuntime>);
+impl ConnectionProvider for PayloadConnectionProvider {
+    type Conn = PayloadConnection;
+    type FutureConn = BoxFuture<'static, Result<PayloadConnection, ResolveError>>;
+    type Time = <TokioRuntime as RuntimeProvider>::Timer;
+
+    fn new_connection (
+        &self,
+        config: &NameServerConfig,
+        options: &ResolverOpts
+    )
+    -> Self::FutureConn {
+        return self.0.new_connection(config, options).map_ok(PayloadConnection).boxed()
+    }
+}
+
+#[derive(Clone)]
+pub struct PayloadConnection(GenericConnection);
+impl DnsHandle for PayloadConnection {
+    type Response = <GenericConnection as DnsHandle>::Response;
+    type Error = ResolveError;
+
+    fn send <R: Into<DnsRequest> + Unpin + Send + 'static> (
+        &mut self,
+        request: R
+    )
+    -> Self::Response {
+        let mut request: DnsRequest = request.into();
+        if let Some(edns) = request.extensions_mut() {
+            edns.set_max_payload(upstream_payload_size());
+        }
+        return self.0.send(request)
+    }
+}
+
+// A resolver has a single connection, it sends each query its cache misses to one of the orders of the forwarders
+// So round robin and random spread the queries over the forwarders behind one cache
+#[derive(Clone)]
+pub struct RotatingConnectionProvider(Arc<Rotation>);
+impl ConnectionProvider for RotatingConnectionProvider {
+    type Conn = RotatingConnection;
+    type FutureConn = Ready<Result<RotatingConnection, ResolveError>>;
+    type Time = <TokioRuntime as RuntimeProvider>::Timer;
+
+    fn new_connection (
+        &self,
+        _config: &NameServerConfig,
+        _options: &ResolverOpts
+    )
+    -> Self::FutureConn {
+        return future::ok(RotatingConnection(self.0.clone()))
+    }
+}
+
+#[derive(Clone)]
+pub struct RotatingConnection(Arc<Rotation>);
+impl DnsHandle for RotatingConnection {
+    type Response = <ForwarderNameServers as DnsHandle>::Response;
+    type Error = ResolveError;
+
+    fn send <R: Into<DnsRequest> + Unpin + Send + 'static> (
+        &mut self,
+        request: R
+    )
+    -> Self::Response {
+        let index = self.0.next_index();
+        return self.0.name_servers[index].clone().send(request)
+    }
+}
+
+// One order of the forwarders per rotation, starting at each of them in turn
+struct Rotation {
+    name_servers: Vec<ForwarderNameServers>,
+    strategy: Option<ForwarderStrategy>,
+    next: AtomicUsize
+}
+impl Rotation {
+    fn next_index (
+        &self
+    )
+    -> usize {
+        return rotation_index(self.strategy, &self.next, self.name_servers.len())
+    }
+}
+
+// Where the next query starts among the forwarders, in 0..len
+fn rotation_index (
+    strategy: Option<ForwarderStrategy>,
+    next: &AtomicUsize,
+    len: usize
+)
+-> usize {
+    if len <= 1 {
+        return 0
+    }
+    return match strategy {
+        Some(ForwarderStrategy::RoundRobin) => next.fetch_add(1, Ordering::Relaxed) % len,
+        Some(ForwarderStrategy::Random) => rand::thread_rng().gen_range(0..len),
+        Some(ForwarderStrategy::Failover) | None => 0
+    }
+}
+
+// The largest UDP answer the client accepts, 512 bytes without EDNS, never more than we advertise
+pub fn udp_payload_limit (
+    request: &Request
+)
+-> usize {
+    let Some(request_edns) = request.edns() else {
+        return MIN_EDNS_PAYLOAD_SIZE as usize
+    };
+    return request_edns.max_payload().clamp(MIN_EDNS_PAYLOAD_SIZE, edns_payload_size()) as usize
+}
+
+// Hands out the resolver to query, the one of the default forwarders or the one of a forward zone
+#[derive(Clone)]
+pub struct ForwarderPool {
+    forwarders: Arc<Vec<Forwarder>>,
+    source_ip: Option<IpAddr>,
+    resolver: Resolver,
+    strategy: Option<ForwarderStrategy>,
+    next: Arc<AtomicUsize>,
+    // In the order of config.forward_zones
+    zones: Arc<Vec<ForwardZone>>
+}
+struct ForwardZone {
+    zone: Name,
+    resolver: Resolver,
+    first_forwarder: Forwarder
+}
+impl ForwarderPool {
+    // The forwarders the resolvers were built with, the probes may have left some out
+    pub fn forwarders (
+        &self
+    )
+    -> &[Forwarder] {
+        return &self.forwarders
+    }
+
+    // Whether one of the forwarders answers a probe, sent to it directly so no cached answer stands in
+    pub async fn any_forwarder_answers (
+        &self
+    )
+    -> bool {
+        let mut pending: Vec<_> = self.forwarders.iter().map(|forwarder| Box::pin(probe(forwarder, self.source_ip))).collect();
+        while !pending.is_empty() {
+            let (healthy, _, remaining) = futures_util::future::select_all(pending).await;
+            if healthy {
+                return true
+            }
+            pending = remaining
+        }
+        return false
+    }
+
+    // The forwarders in the order the ECS queries try them, the forwarder strategy picks the first one
+    pub fn ordered_forwarders (
+        &self
+    )
+    -> Vec<Forwarder> {
+        let mut forwarders = self.forwarders.to_vec();
+        let start = rotation_index(self.strategy, &self.next, forwarders.len());
+        forwarders.rotate_left(start);
+        return forwarders
+    }
+
+    pub fn pick (
+        &self
+    )
+    -> Resolver {
+        return self.resolver.clone()
+    }
+
+    // The resolver of the deepest forward zone the name is under and the first forwarder of the zone
+    pub fn pick_zone (
+        &self,
+        name: &Name
+    )
+    -> Option<(Resolver, Forwarder)> {
+        return self.zones.iter()
+            .find(|forward_zone| forward_zone.zone.zone_of(name))
+            .map(|forward_zone| (forward_zone.resolver.clone(), forward_zone.first_forwarder.clone()))
+    }
+}
+
+// Only these settings end up in the resolvers
+pub fn resolver_changed (
+    old: &Config,
+    new: &Config
+)
+-> bool {
+    return old.forwarders != new.forwarders
+        || old.forward_zones != new.forward_zones
+        || old.forwarder_strategy != new.forwarder_strategy
+        || old.parallel_forwarding != new.parallel_forwarding
+        || old.upstream_timeout != new.upstream_timeout
+        || old.upstream_attempts != new.upstream_attempts
+        || old.dnssec != new.dnssec
+        || old.cache_size != new.cache_size
+        || old.positive_cache != new.positive_cache
+        || old.negative_cache != new.negative_cache
+        || old.cache_min_ttl != new.cache_min_ttl
+        || old.cache_max_ttl != new.cache_max_ttl
+        || old.forwarder_source_ip != new.forwarder_source_ip
+}
+
+// The forwarder_source_ip with any port, None for the forwarders of the other family
+fn source_address (
+    forwarder: SocketAddr,
+    source_ip: Option<IpAddr>
+)
+-> Option<SocketAddr> {
+    return source_ip
+        .filter(|source_ip| source_ip.is_ipv4() == forwarder.is_ipv4())
+        .map(|source_ip| SocketAddr::new(source_ip, 0))
+}
+
+// Encrypted forwarders check the certificate of their tls_name against the webpki roots
+fn add_name_servers (
+    resolver_config: &mut ResolverConfig,
+    forwarder: &Forwarder,
+    source_ip: Option<IpAddr>
+) {
+    let protocols: &[Protocol] = match forwarder.protocol {
+        ForwarderProtocol::Plain => &[Protocol::Udp, Protocol::Tcp],
+        ForwarderProtocol::Tls => &[Protocol::Tls],
+        ForwarderProtocol::Https => &[Protocol::Https]
+    };
+    for protocol in protocols {
+        let mut name_server = NameServerConfig::new(forwarder.address, *protocol);
+        name_server.tls_dns_name = forwarder.tls_name.clone();
+        name_server.bind_addr = source_address(forwarder.address, source_ip);
+        resolver_config.add_name_server(name_server)
+    }
+}
+
 pub fn build_resolver (
     config: &Config
 )
--> AsyncResolver<GenericConnection, GenericConnectionProvider<TokioRuntime>> {
-    let mut resolver_config = ResolverConfig::new();
-    resolver_config.domain();
+-> DnsLrResult<ForwarderPool> {
+    let mut resolver_opts: ResolverOpts = ResolverOpts::default();
+    // The first forwarder to answer wins and the other requests are dropped
+    resolver_opts.num_concurrent_reqs = match config.parallel_forwarding {
+        true => config.forwarders.len(),
+        false => 0
+    };
+    // A slow forwarder then ends in a SERVFAIL instead of keeping the client waiting
+    if let Some(upstream_timeout) = config.upstream_timeout {
+        resolver_opts.timeout = upstream_timeout
+    }
+    if let Some(upstream_attempts) = config.upstream_attempts {
+        resolver_opts.attempts = upstream_attempts
+    }
+    if let Some(cache_size) = config.cache_size {
+        resolver_opts.cache_size = cache_size
+    }
+    let cache_min_ttl = config.cache_min_ttl.map(|ttl| Duration::from_secs(ttl as u64));
+    let cache_max_ttl = config.cache_max_ttl.map(|ttl| Duration::from_secs(ttl as u64));
+    resolver_opts.positive_min_ttl = cache_min_ttl;
+    resolver_opts.negative_min_ttl = cache_min_ttl;
+    resolver_opts.positive_max_ttl = cache_max_ttl;
+    resolver_opts.negative_max_ttl = cache_max_ttl;
+    // A cache is disabled by making its entries expire right away
+    if !config.positive_cache {
+        resolver_opts.positive_min_ttl = None;
+        resolver_opts.positive_max_ttl = Some(Duration::ZERO)
+    }
+    if !config.negative_cache {
+        resolver_opts.negative_min_ttl = None;
+        resolver_opts.negative_max_ttl = Some(Duration::ZERO)
+    }
+    // Bogus answers fail the lookup and are answered with SERVFAIL, lies never reach the resolver
+    if config.dnssec {
+        // The resolver only validates against its built-in root KSK
+        if let Some(dnssec_trust_anchor) = &CONFILE.dnssec_trust_anchor {
+            return Err(WrappedErrors::DNSlrError(ErrorKind::DnssecTrustAnchorUnsupported(dnssec_trust_anchor.clone())))
+        }
+        resolver_opts.validate = true
+    }
 
-    for socket in config.forwarders.clone().into_iter() {
-        let ns_udp = NameServerConfig::new(socket, Protocol::Udp);
-        resolver_config.add_name_server(ns_udp);
-        let ns_tcp = NameServerConfig::new(socket, Protocol::Tcp);
-        resolver_config.add_name_server(ns_tcp);
+    // The queries to the forwarders advertise edns_payload_size too, within what the resolver can read
+    resolver_opts.edns0 = true;
+    if edns_payload_size() > MAX_UPSTREAM_PAYLOAD_SIZE {
+        warn!("{}: edns_payload_size: {} is capped at {} towards the forwarders, the resolver cannot read larger UDP answers", config.daemon_id, edns_payload_size(), MAX_UPSTREAM_PAYLOAD_SIZE)
+    }
+
+    resolver_opts.server_ordering_strategy = match config.forwarder_strategy {
+        // trust-dns ranks the forwarders by their past answers
+        None => ServerOrderingStrategy::QueryStatistics,
+        Some(_) => ServerOrderingStrategy::UserProvidedOrder
+    };
+
+    let resolver = new_resolver(&config.forwarders, config.forwarder_strategy, resolver_opts, config.forwarder_source_ip)?;
+
+    // The forwarders of a zone are tried in their order, the forwarder strategy only spreads the default ones
+    let mut zones = Vec::with_capacity(config.forward_zones.len());
+    for (zone, zone_forwarders) in config.forward_zones.iter() {
+        let mut zone_opts = resolver_opts;
+        if config.parallel_forwarding {
+            zone_opts.num_concurrent_reqs = zone_forwarders.len()
+        }
+        zones.push(ForwardZone {
+            zone: zone.clone(),
+            resolver: new_resolver(zone_forwarders, None, zone_opts, config.forwarder_source_ip)?,
+            first_forwarder: zone_forwarders[0].clone()
+        })
     }
-    
-    let mut resolver_opts: ResolverOpts = ResolverOpts::default();
-    resolver_opts.num_concurrent_reqs = 0;
-    let resolver = TokioAsyncResolver::tokio(
-        resolver_config,
-        resolver_opts
-    ).unwrap();
 
-    info!("{}: Resolver built", config.daemon_id);
-    return resolver
+    info!("{}: Resolver built{}", config.daemon_id, if config.dnssec {" with DNSSEC validation"} else {""});
+    return Ok(ForwarderPool {
+        forwarders: Arc::new(config.forwarders.clone()),
+        source_ip: config.forwarder_source_ip,
+        resolver,
+        strategy: config.forwarder_strategy,
+        next: Arc::new(AtomicUsize::new(0)),
+        zones: Arc::new(zones)
+    })
+}
+
+// Round robin and random rotate the forwarders once per forwarder, the other strategies keep their order
+fn new_resolver (
+    forwarders: &[Forwarder],
+    strategy: Option<ForwarderStrategy>,
+    resolver_opts: ResolverOpts,
+    source_ip: Option<IpAddr>
+)
+-> DnsLrResult<Resolver> {
+    let rotations = match strategy {
+        Some(ForwarderStrategy::RoundRobin | ForwarderStrategy::Random) => forwarders.len().max(1),
+        Some(ForwarderStrategy::Failover) | None => 1
+    };
+    let mut name_servers = Vec::with_capacity(rotations);
+    for rotation in 0..rotations {
+        let mut rotated_config = ResolverConfig::new();
+        for forwarder in forwarders.iter().cycle().skip(rotation).take(forwarders.len()) {
+            add_name_servers(&mut rotated_config, forwarder, source_ip)
+        }
+        name_servers.push(ForwarderNameServers::from_config(
+            rotated_config.name_servers().to_vec().into(),
+            &resolver_opts,
+            PayloadConnectionProvider(GenericConnectionProvider::new(TokioHandle))
+        ))
+    }
+
+    // The resolver only sees the rotating connection, the forwarders behind it are in the name servers above
+    let mut resolver_config = ResolverConfig::new();
+    resolver_config.add_name_server(NameServerConfig::new(([0, 0, 0, 0], 53).into(), Protocol::Udp));
+    return Ok(Resolver::new_with_conn(
+        resolver_config,
+        resolver_opts,
+        RotatingConnectionProvider(Arc::new(Rotation {
+            name_servers,
+            strategy,
+            next: AtomicUsize::new(0)
+        }))
+    )?)
 }
 
 pub async fn get_answers (
     request: &Request,
     mut header: Header,
-    resolver: AsyncResolver<GenericConnection, GenericConnectionProvider<TokioRuntime>>
+    resolver: Resolver
 )
 -> DnsLrResult<(Vec<Record>, Header)> {    
     let mut answers: Vec<Record> =  Vec::new();
@@ -60,7 +431,7 @@ pub async fn get_answers (
         RecordType::MX => wrapped = resolver.lookup(name, RecordType::MX).await,
         RecordType::PTR => {
             let Ok(ip) = name.parse_arpa_name() else {
-                return Err(WrappedErrors::DNSlrError(ErrorKind::InvalidArpaAddress))
+                return Err(WrappedErrors::DNSlrError(ErrorKind::InvalidArpaAddress(name.to_string())))
             };
             
             let ip = ip.addr();
@@ -74,7 +445,10 @@ pub async fn get_answers (
                 Err(error) => {
                     match error.kind() {
                         ResolveErrorKind::NoRecordsFound {response_code: ResponseCode::Refused, ..} => Err(WrappedErrors::DNSlrError(ErrorKind::RequestRefused)),
-                        ResolveErrorKind::NoRecordsFound {..} => Ok((vec![], header)),
+                        ResolveErrorKind::NoRecordsFound {response_code, ..} => {
+                            header.set_response_code(*response_code);
+                            Ok((vec![], header))
+                        },
                         _ => Err(WrappedErrors::ResolverError(error))
                     }
                 }
@@ -97,9 +471,200 @@ pub async fn get_answers (
         Err(error) => {
             match error.kind() {
                 ResolveErrorKind::NoRecordsFound {response_code: ResponseCode::Refused, ..} => Err(WrappedErrors::DNSlrError(ErrorKind::RequestRefused)),
-                ResolveErrorKind::NoRecordsFound {..} => Ok((vec![], header)),
+                ResolveErrorKind::NoRecordsFound {response_code, ..} => {
+                    header.set_response_code(*response_code);
+                    Ok((vec![], header))
+                },
                 _ => Err(WrappedErrors::ResolverError(error))
             }
         }
     }
 }
+
+
+const ECS_TIMEOUT: Duration = Duration::from_secs(5);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+const EDNS_CLIENT_SUBNET: u16 = 8;
+const EDNS_TCP_KEEPALIVE: u16 = 11;
+const EDNS_EXTENDED_ERROR: u16 = 15;
+
+// RFC 7871 option data: family, source prefix length, scope prefix length and the significant address bytes
+fn build_client_subnet (
+    client: IpAddr,
+    prefixes: (u8, u8)
+)
+-> EdnsOption {
+    let (family, prefix, mut address): (u16, u8, Vec<u8>) = match client {
+        IpAddr::V4(ip) => (1, prefixes.0, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (2, prefixes.1, ip.octets().to_vec())
+    };
+
+    address.truncate((prefix as usize).div_ceil(8));
+    if prefix % 8 != 0 {
+        if let Some(last) = address.last_mut() {
+            *last &= 0xff << (8 - prefix % 8)
+        }
+    }
+
+    let mut data = family.to_be_bytes().to_vec();
+    data.push(prefix);
+    data.push(0);
+    data.extend(address);
+    return EdnsOption::Unknown(EDNS_CLIENT_SUBNET, data)
+}
+
+// RFC 7828 option data: the idle timeout in units of 100 milliseconds
+pub fn build_keepalive (
+    timeout: Duration
+)
+-> EdnsOption {
+    let units = (timeout.as_millis() / 100).min(u16::MAX as u128) as u16;
+    return EdnsOption::Unknown(EDNS_TCP_KEEPALIVE, units.to_be_bytes().to_vec())
+}
+
+// RFC 8914 option data: the info code followed by the UTF-8 text
+pub fn build_extended_error (
+    extended_error: ExtendedError,
+    text: &str
+)
+-> EdnsOption {
+    let mut data = extended_error.info_code().to_be_bytes().to_vec();
+    data.extend_from_slice(text.as_bytes());
+    return EdnsOption::Unknown(EDNS_EXTENDED_ERROR, data)
+}
+
+// Sends ". NS" to the forwarder, any answer but SERVFAIL or REFUSED means it is healthy
+// The probes leave from the forwarder_source_ip too, so they pass the same upstream ACLs as the queries
+pub async fn probe (
+    forwarder: &Forwarder,
+    source_ip: Option<IpAddr>
+)
+-> bool {
+    if forwarder.protocol != ForwarderProtocol::Plain {
+        return probe_encrypted(forwarder, source_ip).await
+    }
+    let forwarder = forwarder.address;
+
+    let id = rand::random::<u16>();
+
+    let mut message = Message::new();
+    message.set_id(id);
+    message.set_recursion_desired(true);
+    message.add_query(Query::query(Name::root(), RecordType::NS));
+    let Ok(query) = message.to_vec() else {
+        return false
+    };
+
+    return match timeout(PROBE_TIMEOUT, exchange(forwarder, source_ip, &query, id)).await {
+        Ok(Ok(response)) => !matches!(response.response_code(), ResponseCode::ServFail | ResponseCode::Refused),
+        _ => false
+    }
+}
+
+// Encrypted forwarders are probed through a resolver of their own, so the handshake is part of the probe
+async fn probe_encrypted (
+    forwarder: &Forwarder,
+    source_ip: Option<IpAddr>
+)
+-> bool {
+    let mut resolver_config = ResolverConfig::new();
+    add_name_servers(&mut resolver_config, forwarder, source_ip);
+    let mut resolver_opts = ResolverOpts::default();
+    resolver_opts.cache_size = 0;
+    resolver_opts.attempts = 1;
+    resolver_opts.timeout = PROBE_TIMEOUT;
+    let Ok(resolver) = TokioAsyncResolver::tokio(resolver_config, resolver_opts) else {
+        return false
+    };
+
+    return match timeout(PROBE_TIMEOUT, resolver.lookup(Name::root(), RecordType::NS)).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(error)) => !matches!(error.kind(), ResolveErrorKind::NoRecordsFound {response_code: ResponseCode::ServFail | ResponseCode::Refused, ..} | ResolveErrorKind::Io(_) | ResolveErrorKind::Proto(_) | ResolveErrorKind::Timeout | ResolveErrorKind::NoConnections),
+        Err(_) => false
+    }
+}
+
+async fn exchange (
+    forwarder: SocketAddr,
+    source_ip: Option<IpAddr>,
+    query: &[u8],
+    id: u16
+)
+-> DnsLrResult<Message> {
+    let local: SocketAddr = match (source_address(forwarder, source_ip), forwarder) {
+        (Some(source), _) => source,
+        (None, SocketAddr::V4(_)) => ([0, 0, 0, 0], 0).into(),
+        (None, SocketAddr::V6(_)) => ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(forwarder).await?;
+    socket.send(query).await?;
+
+    let mut buffer = vec![0u8; edns_payload_size() as usize];
+    loop {
+        let read = socket.recv(&mut buffer).await?;
+        let Ok(message) = Message::from_vec(&buffer[..read]) else {
+            continue
+        };
+        if message.id() == id {
+            return Ok(message)
+        }
+    }
+}
+
+// The cached resolver cannot attach EDNS options, so these queries go straight to the forwarders of the pool
+// Those the probes left out are skipped and the forwarder strategy orders them, build_config turns ecs off
+// for encrypted forwarders. Each query gets a socket of its own, so a random source port like the resolver's
+// Returns None when the answer is truncated or there is no forwarder, the caller then falls back to the resolver
+pub async fn get_answers_with_subnet (
+    request: &Request,
+    mut header: Header,
+    config: &Config,
+    forwarders: &[Forwarder],
+    prefixes: (u8, u8)
+)
+-> DnsLrResult<Option<(Vec<Record>, Header, SocketAddr)>> {
+    if forwarders.is_empty() {
+        return Ok(None)
+    }
+    let id = rand::random::<u16>();
+
+    let mut message = Message::new();
+    message.set_id(id);
+    message.set_recursion_desired(true);
+    message.add_query(Query::query(request.query().name().into(), request.query().query_type()));
+    let mut edns = Edns::new();
+    edns.set_max_payload(edns_payload_size());
+    edns.options_mut().insert(build_client_subnet(request.src().ip(), prefixes));
+    message.set_edns(edns);
+    let query = message.to_vec()?;
+
+    let mut last_error = None;
+    for forwarder in forwarders.iter().map(|forwarder| forwarder.address) {
+        let response = match timeout(config.upstream_timeout.unwrap_or(ECS_TIMEOUT), exchange(forwarder, config.forwarder_source_ip, &query, id)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(error)) => {
+                debug!("{}: Forwarder: {} failed the ecs query: {}", config.daemon_id, forwarder, error);
+                last_error = Some(error);
+                continue
+            },
+            Err(_) => {
+                debug!("{}: Forwarder: {} timed out on the ecs query", config.daemon_id, forwarder);
+                continue
+            }
+        };
+
+        if response.truncated() {
+            return Ok(None)
+        }
+        return match response.response_code() {
+            ResponseCode::Refused => Err(WrappedErrors::DNSlrError(ErrorKind::RequestRefused)),
+            response_code => {
+                header.set_response_code(response_code);
+                Ok(Some((response.answers().to_vec(), header, forwarder)))
+            }
+        }
+    }
+
+    return Err(last_error.unwrap_or(WrappedErrors::IOError(std::io::ErrorKind::TimedOut.into())))
+}
\ No newline at end of file
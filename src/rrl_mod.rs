@@ -0,0 +1,111 @@
+use lru::LruCache;
+use std::{
+    net::IpAddr,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant}
+};
+use trust_dns_server::proto::op::{Header, ResponseCode};
+
+// Caps the memory used by the windows when facing a flood of spoofed source IPs
+const MAX_TRACKED_SUBNETS: usize = 65536;
+// Clients are grouped by subnet so that spreading spoofed sources over a network does not help
+const SUBNET_PREFIX_V4: u8 = 24;
+const SUBNET_PREFIX_V6: u8 = 56;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum ResponseKind {
+    Answer,
+    Nodata,
+    Nxdomain,
+    Error
+}
+impl ResponseKind {
+    fn of (
+        header: &Header,
+        answer_count: usize
+    )
+    -> ResponseKind {
+        return match (header.response_code(), answer_count) {
+            (ResponseCode::NoError, 0) => ResponseKind::Nodata,
+            (ResponseCode::NoError, _) => ResponseKind::Answer,
+            (ResponseCode::NXDomain, _) => ResponseKind::Nxdomain,
+            _ => ResponseKind::Error
+        }
+    }
+}
+
+struct Window {
+    start: Instant,
+    responses: u32,
+    limited: u32
+}
+
+// What to do with a response once the limit of its subnet and kind is known
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Send,
+    // Answered with TC=1 and no records, a real client retries over TCP
+    Slip,
+    Drop
+}
+
+fn subnet (
+    client: IpAddr
+)
+-> IpAddr {
+    return match client.to_canonical() {
+        IpAddr::V4(ipv4) => IpAddr::V4((u32::from(ipv4) & (u32::MAX << (32 - SUBNET_PREFIX_V4))).into()),
+        IpAddr::V6(ipv6) => IpAddr::V6((u128::from(ipv6) & (u128::MAX << (128 - SUBNET_PREFIX_V6))).into())
+    }
+}
+
+pub struct ResponseRateLimiter {
+    windows: Mutex<LruCache<(IpAddr, ResponseKind), Window>>
+}
+impl ResponseRateLimiter {
+    pub fn new ()
+    -> ResponseRateLimiter {
+        return ResponseRateLimiter {
+            windows: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_TRACKED_SUBNETS).unwrap()))
+        }
+    }
+
+    // Past limit responses in a window, every slip-th one slips and the others are dropped, a slip of 0 drops them all
+    pub fn check (
+        &self,
+        client: IpAddr,
+        header: &Header,
+        answer_count: usize,
+        (limit, window, slip): (u32, Duration, u32)
+    )
+    -> Verdict {
+        let now = Instant::now();
+        let key = (subnet(client), ResponseKind::of(header, answer_count));
+        let mut windows = self.windows.lock().unwrap();
+        let current = windows.get_or_insert_mut(key, || Window {
+            start: now,
+            responses: 0,
+            limited: 0
+        });
+
+        if now.duration_since(current.start) >= window {
+            *current = Window {
+                start: now,
+                responses: 0,
+                limited: 0
+            }
+        }
+
+        current.responses += 1;
+        if current.responses <= limit {
+            return Verdict::Send
+        }
+        current.limited += 1;
+        return match slip {
+            0 => Verdict::Drop,
+            slip if current.limited.is_multiple_of(slip) => Verdict::Slip,
+            _ => Verdict::Drop
+        }
+    }
+}
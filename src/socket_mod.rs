@@ -0,0 +1,258 @@
+use tokio::net::{TcpListener, UdpSocket};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6}
+};
+#[cfg(unix)]
+use std::ffi::CString;
+
+const TCP_BACKLOG: i32 = 1024;
+
+// Splits an optional "%ifname" device suffix from a bind address, e.g. "0.0.0.0:53%eth0"
+// A '%' inside the brackets of a scoped IPv6 address is not a device suffix
+pub fn split_device (
+    address: &str
+)
+-> (&str, Option<&str>) {
+    return match address.rsplit_once('%') {
+        Some((address, device)) if !device.is_empty() && !device.contains(']') && !device.contains(':') => (address, Some(device)),
+        _ => (address, None)
+    }
+}
+
+// Splits an optional "#policy" suffix from a bind, e.g. "UDP=10.8.0.1:53#vpn"
+pub fn split_policy (
+    bind: &str
+)
+-> (&str, Option<&str>) {
+    return match bind.rsplit_once('#') {
+        Some((bind, policy)) if !policy.is_empty() => (bind, Some(policy)),
+        _ => (bind, None)
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device (
+    socket: &Socket,
+    device: &str,
+    _daemon_id: &str
+)
+-> io::Result<()> {
+    return socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device (
+    _socket: &Socket,
+    device: &str,
+    daemon_id: &str
+)
+-> io::Result<()> {
+    tracing::warn!("{}: Binding to device: {} is not supported on this platform, binding normally", daemon_id, device);
+    return Ok(())
+}
+
+// Lets several processes bind the same address, the kernel spreads the queries over them
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port (
+    socket: &Socket,
+    _daemon_id: &str
+)
+-> io::Result<()> {
+    return socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port (
+    _socket: &Socket,
+    daemon_id: &str
+)
+-> io::Result<()> {
+    tracing::warn!("{}: SO_REUSEPORT is not supported on this platform, binding normally", daemon_id);
+    return Ok(())
+}
+
+fn build_socket (
+    address: SocketAddr,
+    kind: Type,
+    protocol: Protocol,
+    device: Option<&str>,
+    reuseport: bool,
+    daemon_id: &str
+)
+-> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(address), kind, Some(protocol))?;
+    // Like the std TcpListener on Unix, so a restart binds again despite the connections left in TIME_WAIT
+    #[cfg(unix)]
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?
+    }
+    if let Some(device) = device {
+        bind_device(&socket, device, daemon_id)?
+    }
+    if reuseport {
+        set_reuse_port(&socket, daemon_id)?
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+
+    return Ok(socket)
+}
+
+// Two binds overlap when they share a port on the same IP or when either listens on every IP
+pub fn overlaps (
+    first: SocketAddr,
+    second: SocketAddr
+)
+-> bool {
+    return first.port() == second.port()
+        && (first.ip() == second.ip() || first.ip().is_unspecified() || second.ip().is_unspecified())
+}
+
+#[cfg(unix)]
+fn interface_index (
+    interface: &str
+)
+-> io::Result<u32> {
+    let Ok(name) = CString::new(interface) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a valid interface name", interface)))
+    };
+    // SAFETY: name is a valid nul-terminated string that outlives the call
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error())
+    }
+    return Ok(index)
+}
+
+#[cfg(not(unix))]
+fn interface_index (
+    interface: &str
+)
+-> io::Result<u32> {
+    return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Scope {} must be given as an interface index on this platform", interface)))
+}
+
+// std only takes the numeric scope of a link-local address, "[fe80::1%eth0]:53" is read as "[fe80::1%2]:53"
+pub fn parse_address (
+    address: &str
+)
+-> io::Result<SocketAddr> {
+    let error = match address.parse::<SocketAddr>() {
+        Ok(address) => return Ok(address),
+        Err(error) => io::Error::new(io::ErrorKind::InvalidInput, error)
+    };
+
+    let Some((host, port)) = address.strip_prefix('[').and_then(|address| address.rsplit_once("]:")) else {
+        return Err(error)
+    };
+    let Some((ip, interface)) = host.split_once('%') else {
+        return Err(error)
+    };
+    let (Ok(ip), Ok(port)) = (ip.parse::<Ipv6Addr>(), port.parse::<u16>()) else {
+        return Err(error)
+    };
+    return Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, interface_index(interface)?)))
+}
+
+pub async fn bind_udp (
+    address: &str,
+    reuseport: bool,
+    daemon_id: &str
+)
+-> io::Result<UdpSocket> {
+    let (address, device) = split_device(address);
+    let address = parse_address(address)?;
+    if device.is_none() && !reuseport {
+        return UdpSocket::bind(address).await
+    }
+
+    let socket = build_socket(address, Type::DGRAM, Protocol::UDP, device, reuseport, daemon_id)?;
+    return UdpSocket::from_std(socket.into())
+}
+
+pub async fn bind_tcp (
+    address: &str,
+    reuseport: bool,
+    daemon_id: &str
+)
+-> io::Result<TcpListener> {
+    let (address, device) = split_device(address);
+    let address = parse_address(address)?;
+    if device.is_none() && !reuseport {
+        return TcpListener::bind(address).await
+    }
+
+    let socket = build_socket(address, Type::STREAM, Protocol::TCP, device, reuseport, daemon_id)?;
+    socket.listen(TCP_BACKLOG)?;
+    return TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    // The loopback interface is the only one every test host has
+    #[cfg(target_os = "linux")]
+    const LOOPBACK_INTERFACE: &str = "lo";
+
+    fn scoped (
+        scope_id: u32
+    )
+    -> SocketAddr {
+        return SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 53, 0, scope_id))
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn parse_address_resolves_an_interface_scope () {
+        let index = interface_index(LOOPBACK_INTERFACE).unwrap();
+        assert_eq!(parse_address(&format!("[fe80::1%{}]:53", LOOPBACK_INTERFACE)).unwrap(), scoped(index));
+        assert_ne!(index, 0);
+    }
+
+    #[test]
+    fn parse_address_takes_a_numeric_scope () {
+        assert_eq!(parse_address("[fe80::1%2]:53").unwrap(), scoped(2));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn split_device_leaves_the_scope_to_parse_address () {
+        let bind = format!("[fe80::1%{}]:53%eth1", LOOPBACK_INTERFACE);
+        let (address, device) = split_device(&bind);
+        assert_eq!(device, Some("eth1"));
+        assert_eq!(parse_address(address).unwrap(), scoped(interface_index(LOOPBACK_INTERFACE).unwrap()));
+    }
+
+    #[test]
+    fn split_device_ignores_a_scope_without_device () {
+        assert_eq!(split_device("[fe80::1%eth0]:53"), ("[fe80::1%eth0]:53", None));
+        assert_eq!(split_device("[fe80::1%2]:53"), ("[fe80::1%2]:53", None));
+        assert_eq!(split_device("0.0.0.0:53%eth0"), ("0.0.0.0:53", Some("eth0")));
+    }
+
+    #[test]
+    fn parse_address_takes_plain_addresses () {
+        assert_eq!(parse_address("127.0.0.1:53").unwrap(), SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 53)));
+        assert_eq!(parse_address("[::1]:53").unwrap(), SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 53, 0, 0)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn build_socket_sets_reuseaddr_on_tcp_only () {
+        let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let tcp = build_socket(address, Type::STREAM, Protocol::TCP, None, true, "test").unwrap();
+        let udp = build_socket(address, Type::DGRAM, Protocol::UDP, None, true, "test").unwrap();
+        assert!(tcp.reuse_address().unwrap());
+        assert!(!udp.reuse_address().unwrap());
+    }
+
+    #[test]
+    fn parse_address_rejects_an_unknown_interface () {
+        assert!(parse_address("[fe80::1%dnsliar-none0]:53").is_err());
+    }
+}
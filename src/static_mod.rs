@@ -0,0 +1,56 @@
+use crate::enums_structs::{Config, DnsLrResult};
+use crate::{matching, redis_mod};
+
+use trust_dns_client::rr::{RData, RecordType, Record, Name};
+use trust_dns_server::server::Request;
+
+use tracing::{info, warn};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr
+};
+
+// Static records live in "static:{domain}" hashes with one field per qtype, e.g. HSET static:intranet.lan. A 10.0.0.1
+// The domain is looked up in its normalized form, like the names of the matchclasses
+// A and AAAA queries are answered with the CNAME field when the name has no address of that family
+pub async fn lookup (
+    request: &Request,
+    config: &Config,
+    mut redis_manager: redis::aio::ConnectionManager
+)
+-> DnsLrResult<Option<Vec<Record>>> {
+    let qtype = request.query().query_type();
+    if !matches!(qtype, RecordType::A | RecordType::AAAA | RecordType::CNAME) {
+        return Ok(None)
+    }
+
+    let raw_name = request.query().name().to_string();
+    let name = matching::normalize_name(&raw_name).unwrap_or(raw_name);
+    let (value, cname) = redis_mod::get_static(&mut redis_manager, &name, qtype).await?;
+
+    let (value, qtype) = match (value, cname) {
+        (Some(value), _) => (value, qtype),
+        (None, Some(cname)) => (cname, RecordType::CNAME),
+        (None, None) => return Ok(None)
+    };
+
+    let mut answers = vec![];
+    for data in value.split_whitespace() {
+        let rdata = match qtype {
+            RecordType::A => Ipv4Addr::from_str(data).ok().map(RData::A),
+            RecordType::AAAA => Ipv6Addr::from_str(data).ok().map(RData::AAAA),
+            _ => Name::from_str(data).ok().map(RData::CNAME)
+        };
+        let Some(rdata) = rdata else {
+            warn!("{}: Static record: {} {} {} is not valid", config.daemon_id, name, qtype, data);
+            continue
+        };
+        answers.push(Record::from_rdata(request.query().name().into(), config.static_ttl, rdata))
+    }
+
+    if answers.is_empty() {
+        return Ok(None)
+    }
+    info!("{}: Request n°{}: {} was answered from the static records", config.daemon_id, request.id(), name);
+    return Ok(Some(answers))
+}
@@ -0,0 +1,75 @@
+use crate::CONFILE;
+
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{runtime, trace, Resource};
+use trust_dns_server::server::Request;
+
+use tracing::{info_span, field, Level, Span, Subscriber};
+use tracing_subscriber::{filter::Targets, registry::LookupSpan, Layer};
+
+// The query spans are only exported, the log output leaves this target out
+pub const TARGET: &str = "dnsliar::telemetry";
+const SERVICE_NAME: &str = "dnsliar-rs";
+
+// The OTLP layer when otlp_endpoint is set, its spans are batched and sent over HTTP in the background
+pub fn layer <S> ()
+-> Result<Option<impl Layer<S>>, opentelemetry::trace::TraceError>
+where S: Subscriber + for<'span> LookupSpan<'span> {
+    let Some(otlp_endpoint) = &CONFILE.otlp_endpoint else {
+        return Ok(None)
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(otlp_endpoint))
+        .with_trace_config(trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", SERVICE_NAME),
+            KeyValue::new("service.instance.id", CONFILE.daemon_id.clone())
+        ])))
+        .install_batch(runtime::Tokio)?;
+
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(Targets::new().with_target(TARGET, Level::INFO));
+    return Ok(Some(layer))
+}
+
+// Sends the spans still batched, the exporter blocks until it is done
+pub async fn shutdown () {
+    if CONFILE.otlp_endpoint.is_some() {
+        let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+    }
+}
+
+// Each query is the root of its own trace, filtered and upstream are recorded once known
+// Without an exporter nothing enables the target, so the span is disabled and costs next to nothing
+pub fn query_span (
+    request: &Request
+)
+-> Span {
+    return info_span!(
+        target: TARGET,
+        parent: None,
+        "query",
+        qname = %request.query().name(),
+        qtype = %request.query().query_type(),
+        client = %request.src().ip(),
+        filtered = field::Empty
+    )
+}
+
+pub fn match_lookup_span ()
+-> Span {
+    return info_span!(target: TARGET, "match_lookup")
+}
+
+pub fn forward_span ()
+-> Span {
+    return info_span!(target: TARGET, "forward", upstream = field::Empty)
+}
+
+pub fn respond_span ()
+-> Span {
+    return info_span!(target: TARGET, "respond")
+}
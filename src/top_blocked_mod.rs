@@ -0,0 +1,59 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex
+};
+
+// The domains followed at once, whatever the number of distinct domains filtered
+const TRACKED_DOMAINS: usize = 256;
+// The domains shown by the metrics endpoint and SIGUSR2
+pub const TOP_BLOCKED: usize = 10;
+
+// The most filtered domains, counted with the Space-Saving algorithm:
+// once full, a new domain takes the place of the least counted one and inherits its count,
+// so a count may be overestimated by at most the count it inherited
+#[derive(Debug, Default)]
+pub struct TopBlocked {
+    counts: Mutex<HashMap<String, u64>>
+}
+impl TopBlocked {
+    pub fn observe (
+        &self,
+        domain: &str
+    ) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(domain) {
+            *count += 1;
+            return
+        }
+
+        let mut count = 1;
+        if counts.len() >= TRACKED_DOMAINS {
+            let least = counts.iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(domain, count)| (domain.clone(), *count));
+            if let Some((least_domain, least_count)) = least {
+                counts.remove(&least_domain);
+                count += least_count
+            }
+        }
+        counts.insert(domain.to_string(), count);
+    }
+
+    // The most counted domains first
+    pub fn top (
+        &self,
+        limit: usize
+    )
+    -> Vec<(String, u64)> {
+        let mut top: Vec<(String, u64)> = self.counts.lock().unwrap().iter()
+            .map(|(domain, count)| (domain.clone(), *count))
+            .collect();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(limit);
+        return top
+    }
+
+    pub fn reset (&self) {
+        self.counts.lock().unwrap().clear()
+    }
+}